@@ -0,0 +1,47 @@
+use std::io::{Read, Result, Write};
+
+use flate2::{
+    read::{GzDecoder, ZlibDecoder},
+    write::ZlibEncoder,
+    Compression,
+};
+
+const GZIP_MAGIC: [u8;2] = [0x1F, 0x8B];
+
+/// Inflate the body of a compressed KTSR. Both zlib and gzip streams are accepted.
+pub fn decompress(data: &[u8], decomp_size: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(decomp_size);
+
+    if data.starts_with(&GZIP_MAGIC) {
+        GzDecoder::new(data).read_to_end(&mut out)?;
+    } else {
+        ZlibDecoder::new(data).read_to_end(&mut out)?;
+    }
+
+    Ok(out)
+}
+
+/// Deflate the body of a KTSR as a zlib stream
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = ZlibEncoder::new(Vec::with_capacity(data.len() / 2), Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::write::GzEncoder;
+
+    use super::*;
+
+    #[test]
+    fn test_decompress_zlib_and_gzip() {
+        let data: Vec<u8> = (0..0x400u32).map(|i| (i % 7) as u8).collect();
+
+        assert_eq!(decompress(&compress(&data).unwrap(), data.len()).unwrap(), data);
+
+        let mut gzip = GzEncoder::new(vec![], Compression::default());
+        gzip.write_all(&data).unwrap();
+        assert_eq!(decompress(&gzip.finish().unwrap(), data.len()).unwrap(), data);
+    }
+}
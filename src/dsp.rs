@@ -0,0 +1,407 @@
+//! Nintendo DSP ADPCM decoding and encoding.
+//! Every 8 bytes frame starts with a predictor/scale byte, followed by 14 samples of 4 bits.
+
+use std::io::Write;
+
+use crate::error::{Error, Result};
+use crate::sections::{DspBody, DspChannel, Ktss, KtssBody, DSP_FRAME_SIZE, DSP_SAMPLES_PER_FRAME};
+use crate::wav::Wav;
+
+/// Decoding state of a single channel
+pub struct DspDecoder<'a> {
+    coefficients: &'a [i16; 16],
+    history: [i16; 2],
+}
+
+impl<'a> DspDecoder<'a> {
+    pub fn new(channel: &'a DspChannel) -> Self {
+        DspDecoder {
+            coefficients: &channel.coefficients,
+            history: channel.history,
+        }
+    }
+
+    /// Start decoding from the loop context instead, to play the loop again.
+    /// The loop is expected to start on a frame boundary, like Nintendo's encoder does it.
+    pub fn from_loop_context(channel: &'a DspChannel) -> Self {
+        DspDecoder {
+            coefficients: &channel.coefficients,
+            history: channel.loop_history,
+        }
+    }
+
+    pub fn decode_frame(&mut self, frame: &[u8], out: &mut Vec<i16>) {
+        let scale = 1i32 << (frame[0] & 0xF);
+        let index = ((frame[0] >> 4) & 0x7) as usize;
+        let (coef1, coef2) = (self.coefficients[index * 2] as i32, self.coefficients[index * 2 + 1] as i32);
+
+        for byte in &frame[1..] {
+            for nibble in &[byte >> 4, byte & 0xF] {
+                // Sign extend the nibble
+                let nibble = ((*nibble as i8) << 4 >> 4) as i32;
+                let sample = (((nibble * scale) << 11) + 1024 + coef1 * self.history[0] as i32 + coef2 * self.history[1] as i32) >> 11;
+                let sample = sample.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+                self.history = [sample, self.history[0]];
+                out.push(sample);
+            }
+        }
+    }
+}
+
+/// Decode every channel of a DSP body, up to sample_count samples each.
+/// When loop_count is not zero, the loop is played that many more times, starting from the loop context, before what follows it.
+pub fn decode(dsp: &DspBody, sample_count: usize, loop_points: Option<(u32, u32)>, loop_count: usize) -> Vec<Vec<i16>> {
+    dsp.channels.iter().enumerate().map(|(i, channel)| {
+        let mut samples = Vec::with_capacity(sample_count + DSP_SAMPLES_PER_FRAME);
+        let mut decoder = DspDecoder::new(channel);
+
+        for frame in dsp.channel_frames(i) {
+            if samples.len() >= sample_count {
+                break;
+            }
+            decoder.decode_frame(frame, &mut samples);
+        }
+        samples.truncate(sample_count);
+
+        if let Some((start, length)) = loop_points.filter(|_| loop_count > 0) {
+            let first_frame = start as usize / DSP_SAMPLES_PER_FRAME;
+            let skipped = start as usize % DSP_SAMPLES_PER_FRAME;
+            let tail = samples.split_off((start as usize + length as usize).min(samples.len()));
+
+            for _ in 0..loop_count {
+                let mut looped = vec![];
+                let mut decoder = DspDecoder::from_loop_context(channel);
+
+                for frame in dsp.channel_frames(i).skip(first_frame) {
+                    if looped.len() >= skipped + length as usize {
+                        break;
+                    }
+                    decoder.decode_frame(frame, &mut looped);
+                }
+
+                samples.extend(looped.iter().skip(skipped).take(length as usize));
+            }
+
+            samples.extend(tail);
+        }
+
+        samples
+    }).collect()
+}
+
+/// Decode a DSP KTSS to a PCM16 WAV, with its loop points kept in the WAV.
+/// The loop is played loop_count more times, so it can be listened to without a player that handles loops.
+pub fn to_wav(ktss: &Ktss, loop_count: usize) -> Result<Wav> {
+    let dsp = match &ktss.body {
+        KtssBody::Dsp(dsp) => dsp,
+        _ => return Err(Error::UnsupportedCodec { codec: ktss.codec.into() }),
+    };
+
+    let loop_points = if ktss.loop_length > 0 && ktss.loop_start >= 0 { Some((ktss.loop_start as u32, ktss.loop_length)) } else { None };
+    let channels = decode(dsp, ktss.sample_count as usize, loop_points, loop_count);
+    let frame_count = channels.iter().map(Vec::len).min().unwrap_or(0);
+
+    Ok(Wav {
+        channel_count: channels.len() as u16,
+        sample_rate: ktss.sample_rate,
+        samples: (0..frame_count).flat_map(|i| channels.iter().map(move |channel| channel[i])).collect(),
+        loop_points,
+    })
+}
+
+pub fn write_wav<W: Write>(ktss: &Ktss, loop_count: usize, writer: W) -> Result<()> {
+    to_wav(ktss, loop_count)?.write(writer)
+}
+
+/// Number of coefficient pairs a channel can pick from, one per frame
+const PREDICTOR_COUNT: usize = 8;
+const LLOYD_ITERATIONS: usize = 16;
+
+/// Correlation of a block of samples with its two previous samples, enough to get the error of any predictor over it
+#[derive(Debug, Default, Clone, Copy)]
+struct BlockStats {
+    r00: f64,
+    r01: f64,
+    r02: f64,
+    r11: f64,
+    r12: f64,
+    r22: f64,
+}
+
+impl BlockStats {
+    fn new(samples: &[i16], start: usize) -> Self {
+        let mut stats = BlockStats::default();
+        let at = |i: isize| if i < 0 { 0.0 } else { samples.get(i as usize).copied().unwrap_or(0) as f64 };
+
+        for n in start..start + DSP_SAMPLES_PER_FRAME {
+            let n = n as isize;
+            let (x0, x1, x2) = (at(n), at(n - 1), at(n - 2));
+            stats.r00 += x0 * x0;
+            stats.r01 += x0 * x1;
+            stats.r02 += x0 * x2;
+            stats.r11 += x1 * x1;
+            stats.r12 += x1 * x2;
+            stats.r22 += x2 * x2;
+        }
+
+        stats
+    }
+
+    fn add(&mut self, other: &BlockStats) {
+        self.r00 += other.r00;
+        self.r01 += other.r01;
+        self.r02 += other.r02;
+        self.r11 += other.r11;
+        self.r12 += other.r12;
+        self.r22 += other.r22;
+    }
+
+    /// Squared error of predicting the block with this pair of coefficients
+    fn error(&self, (a1, a2): (f64, f64)) -> f64 {
+        self.r00 - 2.0 * a1 * self.r01 - 2.0 * a2 * self.r02 + a1 * a1 * self.r11 + 2.0 * a1 * a2 * self.r12 + a2 * a2 * self.r22
+    }
+
+    /// Coefficients minimizing the squared error over the block
+    fn best_predictor(&self) -> (f64, f64) {
+        let det = self.r11 * self.r22 - self.r12 * self.r12;
+
+        let (a1, a2) = if det.abs() > 1e-6 * (self.r11 * self.r22).max(1.0) {
+            ((self.r01 * self.r22 - self.r02 * self.r12) / det, (self.r02 * self.r11 - self.r01 * self.r12) / det)
+        } else if self.r11 > 0.0 {
+            (self.r01 / self.r11, 0.0)
+        } else {
+            (0.0, 0.0)
+        };
+
+        // Coefficients are stored as 5.11 fixed point
+        let limit = i16::MAX as f64 / 2048.0;
+        (a1.clamp(-limit, limit), a2.clamp(-limit, limit))
+    }
+}
+
+/// Pick the coefficient pairs of a channel, by grouping frames that are best predicted by the same pair
+fn estimate_coefficients(samples: &[i16]) -> [i16; 16] {
+    let blocks: Vec<BlockStats> = (0..samples.len()).step_by(DSP_SAMPLES_PER_FRAME).map(|start| BlockStats::new(samples, start)).collect();
+
+    let mut total = BlockStats::default();
+    blocks.iter().for_each(|block| total.add(block));
+
+    // Start from the predictor of the whole channel, and the ones of a few frames spread by their first coefficient
+    let mut predictors: Vec<(f64, f64)> = blocks.iter().filter(|block| block.r00 > 0.0).map(BlockStats::best_predictor).collect();
+    predictors.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut centroids = vec![total.best_predictor()];
+    for i in 1..PREDICTOR_COUNT {
+        centroids.push(predictors.get(i * predictors.len() / PREDICTOR_COUNT).copied().unwrap_or((0.0, 0.0)));
+    }
+
+    for _ in 0..LLOYD_ITERATIONS {
+        let mut groups = vec![BlockStats::default(); PREDICTOR_COUNT];
+        let mut used = [false; PREDICTOR_COUNT];
+
+        for block in &blocks {
+            let best = (0..PREDICTOR_COUNT).min_by(|&a, &b| block.error(centroids[a]).total_cmp(&block.error(centroids[b]))).unwrap_or(0);
+            groups[best].add(block);
+            used[best] = true;
+        }
+
+        for i in 0..PREDICTOR_COUNT {
+            if used[i] {
+                centroids[i] = groups[i].best_predictor();
+            }
+        }
+    }
+
+    let mut coefficients = [0i16; 16];
+    for (i, (a1, a2)) in centroids.iter().enumerate() {
+        coefficients[i * 2] = (a1 * 2048.0).round() as i16;
+        coefficients[i * 2 + 1] = (a2 * 2048.0).round() as i16;
+    }
+
+    coefficients
+}
+
+/// Encode a frame with a given predictor and scale, returning it along with its squared error and the new history
+fn encode_frame_with(samples: &[i16], coefficients: &[i16; 16], history: [i16; 2], index: usize, scale_shift: u8) -> ([u8; DSP_FRAME_SIZE], i64, [i16; 2]) {
+    let (coef1, coef2) = (coefficients[index * 2] as i32, coefficients[index * 2 + 1] as i32);
+    let scale = 1i32 << scale_shift;
+
+    let mut frame = [0u8; DSP_FRAME_SIZE];
+    frame[0] = ((index as u8) << 4) | scale_shift;
+
+    let mut history = history;
+    let mut error = 0i64;
+
+    for i in 0..DSP_SAMPLES_PER_FRAME {
+        let target = samples.get(i).copied().unwrap_or(0) as i32;
+        let prediction = 1024 + coef1 * history[0] as i32 + coef2 * history[1] as i32;
+
+        // Closest nibble to the target, then decoded exactly like the decoder would to keep the history in sync
+        let distance = (target << 11) - prediction + 1024;
+        let nibble = (distance as f64 / (scale << 11) as f64).round().clamp(-8.0, 7.0) as i32;
+        let decoded = ((((nibble * scale) << 11) + prediction) >> 11).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+        error += ((decoded as i32 - target) as i64).pow(2);
+        history = [decoded, history[0]];
+
+        frame[1 + i / 2] |= ((nibble & 0xF) as u8) << if i % 2 == 0 { 4 } else { 0 };
+    }
+
+    (frame, error, history)
+}
+
+/// Encode a single channel, returning its parameters and its frames.
+/// When a loop start is provided, the loop context is captured from the frame holding it.
+pub fn encode_channel(samples: &[i16], loop_start: Option<u32>) -> (DspChannel, Vec<[u8; DSP_FRAME_SIZE]>) {
+    let mut channel = DspChannel {
+        coefficients: estimate_coefficients(samples),
+        ..Default::default()
+    };
+
+    let loop_frame = loop_start.map(|start| start as usize / DSP_SAMPLES_PER_FRAME);
+    let mut history = [0i16; 2];
+    let mut frames = vec![];
+
+    for (i, block) in samples.chunks(DSP_SAMPLES_PER_FRAME).enumerate() {
+        let (frame, _, next_history) = (0..PREDICTOR_COUNT)
+            .flat_map(|index| (0..12u8).map(move |shift| (index, shift)))
+            .map(|(index, shift)| encode_frame_with(block, &channel.coefficients, history, index, shift))
+            .min_by_key(|(_, error, _)| *error)
+            .unwrap();
+
+        if i == 0 {
+            channel.predictor_scale = frame[0] as u16;
+        }
+
+        if Some(i) == loop_frame {
+            channel.loop_predictor_scale = frame[0] as u16;
+            channel.loop_history = history;
+        }
+
+        history = next_history;
+        frames.push(frame);
+    }
+
+    (channel, frames)
+}
+
+/// Encode every channel of a WAV into a DSP body, with the frames of each channel interleaved
+pub fn encode(wav: &Wav) -> DspBody {
+    let channel_count = wav.channel_count.max(1) as usize;
+    let loop_start = wav.loop_points.map(|(start, _)| start);
+
+    let encoded: Vec<_> = (0..channel_count).map(|channel| {
+        let samples: Vec<i16> = wav.samples.iter().skip(channel).step_by(channel_count).copied().collect();
+        encode_channel(&samples, loop_start)
+    }).collect();
+
+    let frame_count = encoded.iter().map(|(_, frames)| frames.len()).max().unwrap_or(0);
+    let mut data = Vec::with_capacity(frame_count * channel_count * DSP_FRAME_SIZE);
+
+    for i in 0..frame_count {
+        for (_, frames) in &encoded {
+            data.extend(frames.get(i).unwrap_or(&[0; DSP_FRAME_SIZE]));
+        }
+    }
+
+    DspBody::new(encoded.into_iter().map(|(channel, _)| channel).collect(), data)
+}
+
+/// Build a standalone DSP KTSS out of a WAV, keeping its loop points.
+/// `Ktsl::add_sound` embeds the result in a Ktsl2asbin instead.
+pub fn encode_ktss(wav: &Wav) -> Ktss {
+    let mut ktss = Ktss::new(KtssBody::Dsp(encode(wav)));
+    ktss.channel_count = wav.channel_count as u8;
+    ktss.sample_rate = wav.sample_rate;
+    ktss.sample_count = wav.frame_count() as u32;
+
+    if let Some((start, length)) = wav.loop_points {
+        ktss.loop_start = start as i32;
+        ktss.loop_length = length;
+    }
+
+    ktss.recompute_layout();
+    ktss
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_frame() {
+        let mut channel = DspChannel::default();
+        channel.coefficients[2] = 0x800;
+
+        // Coefficient pair 1 with a scale of 2^1, so each sample is the previous one plus twice the nibble
+        let frame = [0x11, 0x12, 0x00, 0x00, 0x00, 0x00, 0x00, 0xF0];
+        let mut samples = vec![];
+        DspDecoder::new(&channel).decode_frame(&frame, &mut samples);
+
+        assert_eq!(&samples[..3], &[2, 6, 6]);
+        assert_eq!(samples[12], 4);
+        assert_eq!(samples.len(), DSP_SAMPLES_PER_FRAME);
+    }
+
+    #[test]
+    fn test_loop_uses_loop_context() {
+        let mut channel = DspChannel::default();
+        channel.coefficients[0] = 0x800;
+        channel.loop_history = [100, 0];
+
+        // A single silent frame: the output only depends on the history
+        let dsp = DspBody::new(vec![channel], vec![0; 8]);
+        let channels = decode(&dsp, 14, Some((0, 14)), 1);
+
+        assert_eq!(channels[0].len(), 28);
+        assert_eq!(channels[0][0], 0);
+        assert_eq!(channels[0][14], 100);
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        // A decaying tone and some silence, on two channels
+        let frames = 14 * 40;
+        let samples: Vec<i16> = (0..frames).flat_map(|i| {
+            let t = i as f64 / 32000.0;
+            let tone = (t * 440.0 * std::f64::consts::TAU).sin() * 12000.0 * (-t * 20.0).exp();
+            vec![tone as i16, (tone * -0.5) as i16]
+        }).collect();
+
+        let wav = Wav {
+            channel_count: 2,
+            sample_rate: 32000,
+            samples,
+            loop_points: Some((14 * 10, 14 * 20)),
+        };
+
+        let ktss = encode_ktss(&wav);
+        assert_eq!(ktss.sample_count, frames as u32);
+        assert_eq!(ktss.loop_start, 14 * 10);
+
+        let decoded = to_wav(&ktss, 0).unwrap();
+        assert_eq!(decoded.samples.len(), wav.samples.len());
+
+        let noise: f64 = wav.samples.iter().zip(&decoded.samples).map(|(a, b)| (*a as f64 - *b as f64).powi(2)).sum();
+        let signal: f64 = wav.samples.iter().map(|a| (*a as f64).powi(2)).sum();
+        // At least 20dB of signal to noise ratio
+        assert!(signal / noise > 100.0, "SNR too low: {}", signal / noise);
+
+        // The loop context is the state of the decoder at the start of the loop
+        let dsp = match &ktss.body {
+            KtssBody::Dsp(dsp) => dsp,
+            _ => unreachable!(),
+        };
+        let loop_start = 14 * 10;
+        assert_eq!(dsp.channels[0].loop_history, [decoded.samples[(loop_start - 1) * 2], decoded.samples[(loop_start - 2) * 2]]);
+
+        // Extra loops go between the end of the loop and the rest of the samples
+        let (loop_start, loop_end) = (loop_start * 2, 14 * 30 * 2);
+        let looped = to_wav(&ktss, 2).unwrap();
+        assert_eq!(looped.samples.len(), decoded.samples.len() + 2 * (loop_end - loop_start));
+        assert_eq!(&looped.samples[..loop_end], &decoded.samples[..loop_end]);
+        assert_eq!(&looped.samples[loop_end..2 * loop_end - loop_start], &decoded.samples[loop_start..loop_end]);
+        assert_eq!(&looped.samples[looped.samples.len() - (decoded.samples.len() - loop_end)..], &decoded.samples[loop_end..]);
+    }
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A file that was expected to be there could not be found
+    MissingInput(PathBuf),
+    /// Writing there would replace one of the input files
+    WouldOverwriteInput(PathBuf),
+    /// Both archives of a pair would be written to the same file
+    SameOutput(PathBuf),
+    /// The magic at the start of a file or structure is not the one expected
+    BadMagic { pos: u64 },
+    /// The checksum of a page or block does not match its content
+    BadChecksum { pos: u64 },
+    /// A section in the KTSR has a magic we don't know of, and could not be skipped
+    UnknownSection { magic: u32, offset: u64 },
+    /// A size stored in a header does not match the actual content
+    SizeMismatch { what: &'static str, expected: u64, found: u64 },
+    /// Writing back a parsed archive did not give the same bytes
+    RoundTripMismatch { offset: u64 },
+    /// The archive does not have an entry with this link ID
+    MissingEntry { link_id: u32 },
+    /// The asbin does not have a companion section for this link ID
+    MissingCompanion { link_id: u32 },
+    /// The asbin already has a sound with this link ID
+    DuplicateLinkId { link_id: u32 },
+    /// Loop points that do not fit in the entry
+    InvalidLoop { start: u32, length: u32, sample_count: u32 },
+    /// The KTSS uses a codec this operation does not handle
+    UnsupportedCodec { codec: u8 },
+    /// The WAV file is not made of 16 bits PCM samples
+    UnsupportedWav,
+    /// An Opus packet is too short to even hold its TOC
+    BadOpusPacket { index: usize },
+    /// The compressed body came out exactly as big as the uncompressed one, so the header can't tell it is compressed
+    CompressedSizeCollision { size: u32 },
+    /// The KTSR body is encrypted, which is not supported yet
+    Encrypted,
+    /// Some entries have a header that does not match their audio
+    ValidationFailed { issue_count: usize },
+    /// Any other parsing error
+    Parse(binread::Error),
+}
+
+impl Error {
+    /// Open a file, reporting a missing file as such instead of a generic I/O error
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<std::fs::File> {
+        std::fs::File::open(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => Error::MissingInput(path.as_ref().to_path_buf()),
+            _ => Error::Io(err),
+        })
+    }
+
+    /// Exit code to use when the command-line tool stops on this error
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 2,
+            Error::MissingInput(_) | Error::WouldOverwriteInput(_) | Error::SameOutput(_) => 3,
+            Error::BadMagic { .. } | Error::BadChecksum { .. } | Error::UnknownSection { .. } | Error::SizeMismatch { .. } | Error::Parse(_) => 4,
+            Error::MissingEntry { .. } | Error::MissingCompanion { .. } | Error::DuplicateLinkId { .. } | Error::InvalidLoop { .. } => 5,
+            Error::RoundTripMismatch { .. } => 6,
+            Error::UnsupportedCodec { .. } | Error::UnsupportedWav | Error::BadOpusPacket { .. } | Error::Encrypted | Error::CompressedSizeCollision { .. } => 7,
+            Error::ValidationFailed { .. } => 8,
+        }
+    }
+
+    /// Wrap the error so it can go through a BinRead implementation and come back out intact
+    pub(crate) fn into_binread(self, pos: u64) -> binread::Error {
+        binread::Error::Custom {
+            pos: pos as usize,
+            err: Box::new(self),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::MissingInput(path) => write!(f, "Could not find {}", path.display()),
+            Error::WouldOverwriteInput(path) => write!(f, "Refusing to overwrite input file {}, use --force to do it anyway", path.display()),
+            Error::SameOutput(path) => write!(f, "The Ktsl2stbin and the Ktsl2asbin would both be written to {}", path.display()),
+            Error::BadMagic { pos } => write!(f, "Unexpected magic at 0x{:x}", pos),
+            Error::BadChecksum { pos } => write!(f, "Bad checksum at 0x{:x}", pos),
+            Error::UnknownSection { magic, offset } => write!(f, "Unknown section magic 0x{:08x} at 0x{:x}", magic, offset),
+            Error::SizeMismatch { what, expected, found } => write!(f, "{} is 0x{:x} bytes but 0x{:x} were expected", what, found, expected),
+            Error::RoundTripMismatch { offset } => write!(f, "The written archive differs from the original starting at 0x{:x}", offset),
+            Error::MissingEntry { link_id } => write!(f, "No entry with link ID {:08x}", link_id),
+            Error::MissingCompanion { link_id } => write!(f, "No companion section with link ID {:08x} in the asbin", link_id),
+            Error::DuplicateLinkId { link_id } => write!(f, "The asbin already has a sound with link ID {:08x}", link_id),
+            Error::InvalidLoop { start, length, sample_count } => write!(f, "A loop of {} samples starting at {} does not fit in {} samples", length, start, sample_count),
+            Error::UnsupportedCodec { codec } => write!(f, "Unsupported KTSS codec 0x{:x}", codec),
+            Error::UnsupportedWav => write!(f, "Only 16 bits PCM WAV files are supported"),
+            Error::BadOpusPacket { index } => write!(f, "Opus packet {} is invalid", index),
+            Error::CompressedSizeCollision { size } => write!(f, "The compressed archive is 0x{:x} bytes just like the uncompressed one, which the header can't tell apart. Write it uncompressed instead", size),
+            Error::Encrypted => write!(f, "Encrypted archives are not supported yet"),
+            Error::ValidationFailed { issue_count } => write!(f, "Found {} issue(s), pack with --recompute to fix them", issue_count),
+            Error::Parse(binread::Error::EnumErrors { pos, variant_errors }) => {
+                write!(f, "Parsing error at 0x{:x}, no variant matched:", pos)?;
+
+                for (variant, err) in variant_errors {
+                    write!(f, "\n    {}: {}", variant, err)?;
+                }
+
+                Ok(())
+            },
+            Error::Parse(err) => write!(f, "Parsing error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl From<binread::Error> for Error {
+    fn from(err: binread::Error) -> Self {
+        match err {
+            binread::Error::Io(err) => Error::Io(err),
+            binread::Error::BadMagic { pos, .. } => Error::BadMagic { pos: pos as u64 },
+            binread::Error::Custom { pos, err } => match err.downcast::<Error>() {
+                Ok(err) => *err,
+                Err(err) => Error::Parse(binread::Error::Custom { pos, err }),
+            },
+            err => Error::Parse(err),
+        }
+    }
+}
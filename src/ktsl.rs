@@ -1,19 +1,29 @@
-/// Koei Tecmo Sound Resource
-/// Reverse engineered by Raytwo
-/// Special thanks to HealingBrew/Yretenai, Devin, Liam and DeathChaos25. Let me know if I forgot someone!
+//! Koei Tecmo Sound Resource
+//! Reverse engineered by Raytwo
+//! Special thanks to HealingBrew/Yretenai, Devin, Liam and DeathChaos25. Let me know if I forgot someone!
 
+use std::convert::TryFrom;
 use std::fs::File;
-use std::path::Path;
-use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::io::{BufReader, Write};
 
 use binread::{
-    io::{Read, Seek, SeekFrom},
-    BinRead, BinResult, ReadOptions,
+    io::{Cursor, Read, Seek, SeekFrom},
+    BinRead, BinReaderExt, BinResult, ReadOptions,
 };
 
-use crate::sections;
-use sections::{ InfoSection, SoundSection, MusicSection, PaddingSection, UnknownSection };
+use binwrite::{
+    BinWrite,
+    WriterOption,
+};
+
+use rayon::prelude::*;
 
+use crate::{compression, sections};
+use crate::error::{Error, Result};
+use sections::{ EmbeddedSoundSection, InfoSection, Ktss, KtssCompanionSection, MusicSection, PaddingSection, RawSection, UnknownSection, Payload, KtssIssue, StreamInfo };
+
+pub const KTSR_HEADER_SIZE: u32 = 0x40;
 
 #[repr(C)]
 #[derive(BinRead, Debug, Clone)]
@@ -21,6 +31,8 @@ use sections::{ InfoSection, SoundSection, MusicSection, PaddingSection, Unknown
 pub struct Ktsr {
     pub magic: [u8;4],
     pub filetype: Filetype,
+    /// Written back as it was read, and 1 in new archives. Compression is told by comp_size alone,
+    /// no flag is known to go with it, but this hasn't been checked against compressed retail files.
     pub flags: u16,
     pub platform: Platform,
     pub game: Game,
@@ -39,10 +51,20 @@ pub enum Platform {
     PC,
     #[br(magic = 0x400u16)]
     Switch,
-    Unknown(u32)
+    Unknown(u16)
     // ...
 }
 
+impl BinWrite for Platform {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> std::io::Result<()> {
+        match self {
+            Platform::PC => 0x100u16,
+            Platform::Switch => 0x400u16,
+            Platform::Unknown(id) => *id,
+        }.write_options(writer, options)
+    }
+}
+
 #[derive(BinRead, Debug, Clone)]
 #[br(little)]
 pub enum Game {
@@ -52,7 +74,16 @@ pub enum Game {
     // ...
 }
 
-#[derive(BinRead, Debug, Clone)]
+impl BinWrite for Game {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> std::io::Result<()> {
+        match self {
+            Game::ThreeHouses => 0xB75674CEu32,
+            Game::Unknown(id) => *id,
+        }.write_options(writer, options)
+    }
+}
+
+#[derive(BinRead, Debug, Clone, Copy, PartialEq)]
 #[br(little)]
 /// The type of content in the KTSR
 pub enum Filetype {
@@ -64,13 +95,46 @@ pub enum Filetype {
     Stream,
 }
 
+impl BinWrite for Filetype {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> std::io::Result<()> {
+        match self {
+            Filetype::Asset => 0x1A487B77u32,
+            Filetype::Stream => 0xFCDD9402u32,
+        }.write_options(writer, options)
+    }
+}
+
+/// What the entries of a Ktsl2stbin are written as when unpacking
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// The KTSS as stored in the archive. KOVS are always written as plain Ogg Vorbis, whatever the format.
+    Ktss,
+    /// The Opus stream of the KTSS remuxed into an Ogg Opus file
+    Ogg,
+    /// The DSP ADPCM stream of the KTSS decoded to a PCM16 WAV file
+    Wav,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        match src.to_ascii_lowercase().as_str() {
+            "ktss" => Ok(ExportFormat::Ktss),
+            "ogg" | "opus" => Ok(ExportFormat::Ogg),
+            "wav" => Ok(ExportFormat::Wav),
+            _ => Err(format!("Unknown format \"{}\", expected ktss, ogg or wav", src)),
+        }
+    }
+}
+
 /// Header used by both Ktsl2asbin and Ktsl2stbin
 impl Ktsr {
-    pub fn new() -> Self {
+    pub fn new(filetype: Filetype) -> Self {
         // Temp
         Ktsr {
             magic: *b"KTSR",
-            filetype: Filetype::Stream,
+            filetype,
             flags: 1,
             // TODO: Ask it in argument or serialize in a json?
             platform: Platform::Switch,
@@ -85,6 +149,24 @@ impl Ktsr {
     }
 }
 
+impl Ktsr {
+    pub fn is_compressed(&self) -> bool {
+        self.comp_size != self.decomp_size
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        !self.enc_seed.is_empty()
+    }
+}
+
+impl BinWrite for Ktsr {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> std::io::Result<()> {
+        (self.magic, &self.filetype, self.flags, &self.platform, &self.game, self.padding, self.decomp_size, self.comp_size, self.enc_seed.len() as u8, &self.enc_seed).write_options(writer, options)?;
+        // The sections always start at 0x40
+        vec![0u8; (KTSR_HEADER_SIZE as usize).saturating_sub(0x21 + self.enc_seed.len())].write_options(writer, options)
+    }
+}
+
 #[derive(BinRead, Debug, Clone)]
 #[br(little)]
 pub enum Section {
@@ -93,91 +175,607 @@ pub enum Section {
     Info(InfoSection),
     // Contains either a KTSS/KOVS/RIFF descriptor or a embedded GCADPCM (or whatever they use on other platforms than the Switch)
     #[br(magic = 0x70CBCCC5u32)]
-    Sound(SoundSection),
+    Sound(KtssCompanionSection),
+    // Same magic, told apart by the subsection magic
+    #[br(magic = 0x70CBCCC5u32)]
+    EmbeddedSound(EmbeddedSoundSection),
     #[br(magic = 0x15F4D409u32)]
     Music(MusicSection),
     #[br(magic = 0xA8DB7261u32)]
     Padding(PaddingSection),
-    #[br(magic = 0x368C88BDu32)]
+    #[br(magic = 0xF13BD2A9u32)]
     Unknown(UnknownSection),
+    // Anything else, so new games and DLCs at least survive a round-trip
+    Raw(RawSection),
 }
 
-#[repr(C)]
-#[derive(BinRead, Debug, Copy, Clone)]
-pub struct Srsa {
-    pub unk: [u32;3],
+impl Section {
+    pub fn is_known_magic(magic: u32) -> bool {
+        matches!(magic, 0x368C88BD | 0x70CBCCC5 | 0x15F4D409 | 0xA8DB7261 | 0xF13BD2A9)
+    }
+
+    pub fn magic(&self) -> u32 {
+        match self {
+            Section::Info(_) => 0x368C88BD,
+            Section::Sound(_) | Section::EmbeddedSound(_) => 0x70CBCCC5,
+            Section::Music(_) => 0x15F4D409,
+            Section::Padding(_) => 0xA8DB7261,
+            Section::Unknown(_) => 0xF13BD2A9,
+            Section::Raw(raw) => raw.magic,
+        }
+    }
 }
 
-#[repr(C)]
-#[derive(BinRead, Debug, Copy, Clone)]
-pub struct Srst {
-    pub unk: [u32;3],
+impl Section {
+    /// Short name of the kind of section, for display
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Section::Info(_) => "info",
+            Section::Sound(_) => "companion",
+            Section::EmbeddedSound(_) => "sound",
+            Section::Music(_) => "entry",
+            Section::Padding(_) => "padding",
+            Section::Unknown(_) => "unknown",
+            Section::Raw(_) => "raw",
+        }
+    }
+
+    /// Link ID of the entry this section belongs to, for the sections that have one
+    pub fn link_id(&self) -> Option<u32> {
+        match self {
+            Section::Info(info) => Some(info.link_id),
+            Section::Sound(sound) => Some(sound.header.link_id),
+            Section::EmbeddedSound(sound) => Some(sound.header.link_id),
+            Section::Music(music) => Some(music.link_id),
+            Section::Padding(_) | Section::Unknown(_) | Section::Raw(_) => None,
+        }
+    }
+
+    pub fn section_size(&self) -> u32 {
+        match self {
+            Section::Info(info) => info.section_size,
+            Section::Sound(sound) => sound.header.section_size,
+            Section::EmbeddedSound(sound) => sound.header.section_size,
+            Section::Music(music) => music.section_size,
+            Section::Padding(padding) => padding.section_size,
+            Section::Unknown(unk) => unk.section_size,
+            Section::Raw(raw) => raw.section_size,
+        }
+    }
 }
 
-#[derive(BinRead, Debug, Clone)]
-#[br(little)]
-pub enum KtslLayout {
-    #[br(magic = b"SRSA")]
-    Srsa(Srsa),
-    #[br(magic = b"SRST")]
-    Srst(Srst),
+impl BinWrite for Section {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> std::io::Result<()> {
+        let magic = self.magic();
+
+        match self {
+            Section::Info(info) => (magic, info).write_options(writer, options),
+            Section::Sound(sound) => (magic, sound).write_options(writer, options),
+            Section::EmbeddedSound(sound) => (magic, sound).write_options(writer, options),
+            Section::Music(music) => (magic, music).write_options(writer, options),
+            Section::Padding(padding) => (magic, padding).write_options(writer, options),
+            Section::Unknown(unk) => (magic, unk).write_options(writer, options),
+            // The magic is part of the raw section already
+            Section::Raw(raw) => raw.write_options(writer, options),
+        }
+    }
+}
+
+/// What is known about a single section, as listed by `print`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectionSummary {
+    pub kind: &'static str,
+    pub magic: u32,
+    /// Offset in the decompressed archive
+    pub offset: u32,
+    pub size: u32,
+    pub link_id: Option<u32>,
+    pub codec: Option<String>,
+    /// The stream described by an entry or a companion section. Companion sections only store the loop start, the loop is assumed to run to the end.
+    pub stream: Option<StreamInfo>,
+    /// Offset and size of the entry in the Ktsl2stbin, according to a companion section
+    pub entry_location: Option<(u32, u32)>,
 }
 
 // Ktsl2stbin and Ktsl2asbin are actually the exact same container with different structs inside. This structure represents their format.
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Ktsl {
-    //pub rdb_header: Option<KtslLayout>,
     pub header: Ktsr,
     // Use a custom reader for this maybe?
     pub entries: Vec<Section>,
+    /// Deflate the sections when writing. Set when reading a compressed archive.
+    pub compressed: bool,
 }
 
 impl Ktsl {
-    pub fn new_asbin() -> Self {
+    pub fn new(filetype: Filetype) -> Self {
         Ktsl {
-            //rdb_header: None,
-            header: Ktsr {
-                filetype: Filetype::Asset,
-                .. Ktsr::new()
-            },
+            header: Ktsr::new(filetype),
             entries: vec![],
+            compressed: false,
         }
     }
 
+    pub fn new_asbin() -> Self {
+        Self::new(Filetype::Asset)
+    }
+
     pub fn new_stbin() -> Self {
-        Ktsl {
-            //rdb_header: None,
-            header: Ktsr {
-                filetype: Filetype::Stream,
-                .. Ktsr::new()
+        Self::new(Filetype::Stream)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::read(&mut BufReader::new(Error::open(path)?))?)
+    }
+
+    /// Write the archive to a temporary file next to the destination, then move it in place so a failure never leaves a truncated archive behind
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp_path = self.save_tmp(path)?;
+
+        Ok(std::fs::rename(&tmp_path, path)?)
+    }
+
+    /// Save a Ktsl2stbin along with the Ktsl2asbin describing it.
+    /// Both are written to temporary files before either is moved in place, so a failure can't leave a new asbin next to an old stbin.
+    pub fn save_pair<P: AsRef<Path>, Q: AsRef<Path>>(stbin: &Ktsl, stbin_path: P, asbin: &Ktsl, asbin_path: Q) -> Result<()> {
+        let stbin_tmp = stbin.save_tmp(stbin_path.as_ref())?;
+
+        let asbin_tmp = match asbin.save_tmp(asbin_path.as_ref()) {
+            Ok(asbin_tmp) => asbin_tmp,
+            Err(err) => {
+                let _ = std::fs::remove_file(&stbin_tmp);
+                return Err(err);
             },
-            entries: vec![],
+        };
+
+        if let Err(err) = std::fs::rename(&stbin_tmp, stbin_path) {
+            let _ = std::fs::remove_file(&stbin_tmp);
+            let _ = std::fs::remove_file(&asbin_tmp);
+            return Err(err.into());
+        }
+
+        Ok(std::fs::rename(&asbin_tmp, asbin_path)?)
+    }
+
+    /// Write the archive next to the destination under a temporary name, and return that name
+    fn save_tmp(&self, path: &Path) -> Result<PathBuf> {
+        let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+
+        let written = File::create(&tmp_path).and_then(|file| {
+            let mut writer = std::io::BufWriter::new(file);
+            self.write(&mut writer)?;
+            writer.into_inner().map_err(|err| err.into_error())?.sync_all()
+        });
+
+        if let Err(err) = written {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(err.into());
         }
+
+        Ok(tmp_path)
     }
 
-    pub fn open<P: AsRef<Path>>(path: P) -> BinResult<Self> {
-        Self::read(&mut BufReader::new(File::open(path)?))
+    /// Parse an archive and write it back, making sure nothing changed in the process.
+    /// Compressed archives are compared once decompressed, as the compressor is not the one the games were built with.
+    pub fn verify_roundtrip(original: &[u8]) -> Result<()> {
+        let ktsl = Ktsl::read(&mut Cursor::new(original))?;
+
+        let mut written = vec![];
+        ktsl.write(&mut written)?;
+
+        let (expected, found) = if ktsl.compressed {
+            let written_header = Ktsr::read(&mut Cursor::new(&written))?;
+
+            // comp_size is the only header field allowed to change, everything else is compared
+            let mut expected = read_decoded(&mut Cursor::new(original), &ktsl.header)?;
+            expected[..0x1C].copy_from_slice(&original[..0x1C]);
+            expected[0x20..0x40].copy_from_slice(&original[0x20..0x40]);
+            let mut found = read_decoded(&mut Cursor::new(&written), &written_header)?;
+            found[..0x1C].copy_from_slice(&written[..0x1C]);
+            found[0x20..0x40].copy_from_slice(&written[0x20..0x40]);
+
+            (expected, found)
+        } else {
+            (original.to_vec(), written)
+        };
+
+        match expected.iter().zip(found.iter()).position(|(a, b)| a != b) {
+            Some(offset) => Err(Error::RoundTripMismatch { offset: offset as u64 }),
+            None if expected.len() != found.len() => Err(Error::SizeMismatch {
+                what: "The written archive",
+                expected: expected.len() as u64,
+                found: found.len() as u64,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Iterate over the sections along with their offset in the decompressed file
+    pub fn section_offsets(&self) -> impl Iterator<Item = (u32, &Section)> {
+        self.entries.iter().scan(KTSR_HEADER_SIZE, |offset, section| {
+            let current = *offset;
+            *offset += section.section_size();
+            Some((current, section))
+        })
     }
+
+    /// Describe every section, in order. Entries that can't be parsed further are still listed, without stream informations.
+    pub fn summaries(&self) -> Vec<SectionSummary> {
+        self.section_offsets().map(|(offset, section)| {
+            let mut summary = SectionSummary {
+                kind: section.kind(),
+                magic: section.magic(),
+                offset,
+                size: section.section_size(),
+                link_id: section.link_id(),
+                codec: None,
+                stream: None,
+                entry_location: None,
+            };
+
+            match section {
+                Section::Music(music) => {
+                    summary.codec = Some(music.payload.codec_name());
+                    summary.stream = music.payload.info().ok();
+                },
+                Section::Sound(sound) => {
+                    summary.stream = Some(StreamInfo {
+                        channel_count: sound.channel_count as u8,
+                        sample_rate: sound.sample_rate,
+                        sample_count: sound.sample_count,
+                        loop_points: u32::try_from(sound.loop_start).ok().map(|start| (start, sound.sample_count.saturating_sub(start))),
+                    });
+                    summary.entry_location = Some((sound.ktss_offset, sound.ktss_size));
+                },
+                Section::EmbeddedSound(sound) => {
+                    summary.codec = Some(format!("{:?} (embedded)", sound.codec()));
+                    summary.stream = Some(StreamInfo {
+                        channel_count: sound.channel_count as u8,
+                        sample_rate: sound.sample_rate,
+                        sample_count: sound.sample_count,
+                        loop_points: u32::try_from(sound.loop_start).ok().map(|start| (start, sound.sample_count.saturating_sub(start))),
+                    });
+                },
+                _ => (),
+            }
+
+            summary
+        }).collect()
+    }
+
+    pub fn get_companion_sections(&mut self) -> Vec<&mut KtssCompanionSection> {
+        self.entries.iter_mut().filter_map(|section| {
+            if let Section::Sound(sound) = section {
+                return Some(sound)
+            }
+
+            None
+        }).collect()
+    }
+
+    pub fn get_music_sections(&self) -> Vec<&MusicSection> {
+        self.entries.iter().filter_map(|section| {
+            if let Section::Music(music) = section {
+                return Some(music)
+            }
+
+            None
+        }).collect()
+    }
+
+    /// Builds the entries of a Ktsl2stbin from a directory of KTSS files, and updates the companion sections of the Ktsl2asbin accordingly.
+    /// Sample counts, frame counts and loops that don't match the audio are returned as (link ID, issue) pairs.
+    /// With `recompute` they are fixed first, otherwise they are packed as they are.
+    /// **Warning**: gross
+    pub fn pack<P: AsRef<Path>>(&mut self, dir: P, asbin: &mut Ktsl, recompute: bool) -> Result<Vec<(u32, KtssIssue)>> {
+        let mut issues = vec![];
+
+        for companion in asbin.get_companion_sections() {
+            let mut payload = Payload::from_file(find_entry_input(dir.as_ref(), companion.header.link_id))?;
+
+            let found = if recompute { payload.recompute()? } else { payload.validate()? };
+            issues.extend(found.into_iter().map(|issue| (companion.header.link_id, issue)));
+            payload.recompute_layout();
+
+            let music = MusicSection::from_payload(companion.header.link_id, payload);
+            companion.sync_with(&music.payload)?;
+
+            self.entries.push(Section::Music(music));
+        }
+
+        self.update_companion_offsets(asbin);
+
+        self.header.game = asbin.header.game.clone();
+        self.header.decomp_size = KTSR_HEADER_SIZE + self.entries.iter().map(Section::section_size).sum::<u32>();
+        self.header.comp_size = self.header.decomp_size;
+
+        Ok(issues)
+    }
+
+    /// Point every companion section of the Ktsl2asbin to where its entry currently is in this Ktsl2stbin
+    fn update_companion_offsets(&self, asbin: &mut Ktsl) {
+        let offsets: Vec<(u32, u32)> = self.section_offsets().filter_map(|(offset, section)| match section {
+            Section::Music(music) => Some((music.link_id, music.ktss_offset(offset))),
+            _ => None,
+        }).collect();
+
+        for companion in asbin.get_companion_sections() {
+            if let Some((_, offset)) = offsets.iter().find(|(id, _)| *id == companion.header.link_id) {
+                companion.ktss_offset = *offset;
+            }
+        }
+    }
+
+    /// Add a sound stored in this Ktsl2asbin itself rather than in the Ktsl2stbin, after every other section.
+    /// Only DSP KTSS can be embedded.
+    pub fn add_sound(&mut self, link_id: u32, name: &str, ktss: &Ktss) -> Result<()> {
+        if self.entries.iter().any(|section| matches!(section, Section::Sound(_) | Section::EmbeddedSound(_)) && section.link_id() == Some(link_id)) {
+            return Err(Error::DuplicateLinkId { link_id });
+        }
+
+        let offset = KTSR_HEADER_SIZE + self.entries.iter().map(Section::section_size).sum::<u32>();
+        self.entries.push(Section::EmbeddedSound(EmbeddedSoundSection::from_ktss(link_id, name.as_bytes(), ktss, offset)?));
+
+        self.header.decomp_size = offset + self.entries.last().map(Section::section_size).unwrap_or(0);
+        self.header.comp_size = self.header.decomp_size;
+
+        Ok(())
+    }
+
+    /// The KTSS of an entry, or one rebuilt from a sound embedded in a Ktsl2asbin, to decode.
+    /// Entries holding KOVS or RIFF are not KTSS, and are reported missing.
+    pub fn get_ktss(&self, link_id: u32) -> Result<Ktss> {
+        self.entries.iter().find_map(|section| match section {
+            Section::Music(music) if music.link_id == link_id => match &music.payload {
+                Payload::Ktss(ktss) => Some(Ok(ktss.clone())),
+                _ => None,
+            },
+            Section::EmbeddedSound(sound) if sound.header.link_id == link_id => Some(sound.to_ktss()),
+            _ => None,
+        }).unwrap_or(Err(Error::MissingEntry { link_id }))
+    }
+
+    pub fn get_music_section(&self, link_id: u32) -> Option<&MusicSection> {
+        self.get_music_sections().into_iter().find(|music| music.link_id == link_id)
+    }
+
+    /// Replaces the payload of a single entry and updates the companion sections of the Ktsl2asbin accordingly.
+    pub fn inject<P: Into<Payload>>(&mut self, link_id: u32, payload: P, asbin: &mut Ktsl) -> Result<()> {
+        if !asbin.get_companion_sections().iter().any(|companion| companion.header.link_id == link_id) {
+            return Err(Error::MissingCompanion { link_id });
+        }
+
+        let mut payload = payload.into();
+        payload.recompute_layout();
+
+        let section = self.entries.iter_mut().find(|section| matches!(section, Section::Music(music) if music.link_id == link_id));
+
+        match section {
+            Some(section) => *section = Section::Music(MusicSection::from_payload(link_id, payload)),
+            None => return Err(Error::MissingEntry { link_id }),
+        }
+
+        self.header.decomp_size = KTSR_HEADER_SIZE + self.entries.iter().map(Section::section_size).sum::<u32>();
+        self.header.comp_size = self.header.decomp_size;
+
+        // Every entry following the one we replaced has moved
+        self.update_companion_offsets(asbin);
+        self.sync_companion(link_id, asbin)
+    }
+
+    pub fn unpack(&self, out_dir: &Path, format: ExportFormat) -> Result<()> {
+        self.get_music_sections().par_iter().try_for_each(|music| match format {
+            ExportFormat::Ktss => music.export(out_dir),
+            ExportFormat::Ogg => music.export_ogg(out_dir),
+            ExportFormat::Wav => music.export_wav(out_dir),
+        })
+    }
+
+    /// Change the loop points of a single entry, or remove them with None, and update its companion section in the Ktsl2asbin accordingly.
+    /// The loop start and length are in samples.
+    pub fn set_loop(&mut self, link_id: u32, loop_points: Option<(u32, u32)>, asbin: &mut Ktsl) -> Result<()> {
+        if !asbin.get_companion_sections().iter().any(|companion| companion.header.link_id == link_id) {
+            return Err(Error::MissingCompanion { link_id });
+        }
+
+        let music = self.entries.iter_mut().find_map(|section| match section {
+            Section::Music(music) if music.link_id == link_id => Some(music),
+            _ => None,
+        }).ok_or(Error::MissingEntry { link_id })?;
+
+        if let Some((start, length)) = loop_points {
+            let sample_count = music.payload.info()?.sample_count;
+
+            if length == 0 || start as u64 + length as u64 > sample_count as u64 {
+                return Err(Error::InvalidLoop { start, length, sample_count });
+            }
+        }
+
+        let mut payload = music.payload.clone();
+        payload.set_loop(loop_points)?;
+
+        // RIFF loops live in a chunk of their own, so the entry can change size and move the ones following it
+        if payload.size() != music.ktss_size {
+            return self.inject(link_id, payload, asbin);
+        }
+
+        music.payload = payload;
+
+        self.update_companion_offsets(asbin);
+        self.sync_companion(link_id, asbin)
+    }
+
+    /// Copy what the game needs to know about an entry to its companion section
+    fn sync_companion(&self, link_id: u32, asbin: &mut Ktsl) -> Result<()> {
+        let music = self.get_music_section(link_id).ok_or(Error::MissingEntry { link_id })?;
+
+        match asbin.get_companion_sections().into_iter().find(|companion| companion.header.link_id == link_id) {
+            Some(companion) => companion.sync_with(&music.payload),
+            None => Err(Error::MissingCompanion { link_id }),
+        }
+    }
+
+    /// Check every entry against its audio, as (link ID, issue) pairs
+    pub fn validate(&self) -> Result<Vec<(u32, KtssIssue)>> {
+        let mut issues = vec![];
+
+        for music in self.get_music_sections() {
+            issues.extend(music.payload.validate()?.into_iter().map(|issue| (music.link_id, issue)));
+        }
+
+        Ok(issues)
+    }
+
+    /// Only export the entries matching the link IDs provided. Nothing is written if one of them can't be found.
+    pub fn extract(&self, link_ids: &[u32], out_dir: &Path) -> Result<()> {
+        let entries = link_ids.iter().map(|&link_id| self.get_music_section(link_id).ok_or(Error::MissingEntry { link_id })).collect::<Result<Vec<_>>>()?;
+
+        for music in entries {
+            music.export(out_dir)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Find the file to pack for an entry, named after its link ID. A KTSS, an Ogg Opus, an Ogg Vorbis, a WAV or an AT9 file is accepted, with the ID in any case.
+fn find_entry_input(dir: &Path, link_id: u32) -> PathBuf {
+    let candidates: Vec<String> = ["ktss", "opus", "ogg", "wav", "at9"].iter()
+        .flat_map(|extension| vec![format!("{:08X}.{}", link_id, extension), format!("{:08x}.{}", link_id, extension)])
+        .collect();
+
+    candidates.iter().map(|name| dir.join(name)).find(|path| path.exists()).unwrap_or_else(|| dir.join(&candidates[0]))
 }
 
 impl BinRead for Ktsl {
     type Args = ();
 
     fn read_options<R: Read + Seek>(reader: &mut R, _options: &ReadOptions, _args: Self::Args) -> BinResult<Self> {
-        let mut ktsl = Ktsl {
-            header: Ktsr::read(reader)?,
-            entries: vec![],
+        let header = Ktsr::read(reader)?;
+
+        // The cipher has not been figured out yet, so there is no point in reading garbage
+        if header.is_encrypted() {
+            return Err(Error::Encrypted.into_binread(0x20));
+        }
+
+        reader.seek(SeekFrom::Start(KTSR_HEADER_SIZE as u64))?;
+
+        let entries = if header.is_compressed() {
+            let mut cursor = Cursor::new(read_decoded(reader, &header)?);
+            cursor.set_position(KTSR_HEADER_SIZE as u64);
+
+            read_sections(&mut cursor, header.decomp_size)?
+        } else {
+            read_sections(reader, header.decomp_size)?
         };
 
-        reader.seek(SeekFrom::Start(0x40)).unwrap();
+        Ok(Ktsl {
+            compressed: header.is_compressed(),
+            header,
+            entries,
+        })
+    }
+}
+
+/// Decompress the body following the header.
+/// The result starts with zeroes in place of the header so alignments and offsets stay relative to the start of the file.
+fn read_decoded<R: Read + Seek>(reader: &mut R, header: &Ktsr) -> BinResult<Vec<u8>> {
+    reader.seek(SeekFrom::Start(KTSR_HEADER_SIZE as u64))?;
+
+    let mut body = vec![0u8; header.comp_size.saturating_sub(KTSR_HEADER_SIZE) as usize];
+    reader.read_exact(&mut body)?;
+
+    if header.is_compressed() {
+        body = compression::decompress(&body, header.decomp_size as usize)?;
+    }
+
+    let mut decoded = vec![0u8; KTSR_HEADER_SIZE as usize];
+    decoded.extend(body);
 
-        while ktsl.header.decomp_size != binread::io::Seek::seek(reader, SeekFrom::Current(0))? as u32 {
-            let section = Section::read(reader)?;
+    if decoded.len() != header.decomp_size as usize {
+        return Err(Error::SizeMismatch {
+            what: "The decoded KTSR",
+            expected: header.decomp_size as u64,
+            found: decoded.len() as u64,
+        }.into_binread(KTSR_HEADER_SIZE as u64));
+    }
 
-            ktsl.entries.push(section);
+    Ok(decoded)
+}
+
+fn read_sections<R: Read + Seek>(reader: &mut R, end: u32) -> BinResult<Vec<Section>> {
+    let mut entries = vec![];
+
+    loop {
+        let offset = reader.seek(SeekFrom::Current(0))?;
+
+        if offset == end as u64 {
+            break;
+        }
+
+        if offset > end as u64 {
+            return Err(Error::SizeMismatch {
+                what: "The sections of the KTSR",
+                expected: end as u64,
+                found: offset,
+            }.into_binread(offset));
+        }
+
+        let magic: u32 = reader.read_le()?;
+        reader.seek(SeekFrom::Start(offset))?;
+
+        match Section::read(reader) {
+            Ok(section) => entries.push(section),
+            // Even kept raw, a section with a nonsensical size can't be skipped
+            Err(_) if !Section::is_known_magic(magic) => return Err(Error::UnknownSection { magic, offset }.into_binread(offset)),
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(entries)
+}
+
+impl BinWrite for Ktsl {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> std::io::Result<()> {
+        // Derived from the sections so the header can't go stale after an edit
+        let decomp_size = KTSR_HEADER_SIZE + self.entries.iter().map(Section::section_size).sum::<u32>();
+
+        if self.header.is_encrypted() {
+            return Err(std::io::Error::other(Error::Encrypted.to_string()));
+        }
+
+        if !self.compressed {
+            let header = Ktsr {
+                decomp_size,
+                comp_size: decomp_size,
+                .. self.header.clone()
+            };
+
+            header.write_options(writer, options)?;
+            return self.entries.write_options(writer, options);
         }
 
-        Ok(ktsl)
+        let mut body = vec![];
+        self.entries.write_options(&mut body, options)?;
+        let body = compression::compress(&body)?;
+
+        // As far as we know, the games only rely on comp_size differing from decomp_size to know the body is compressed,
+        // so a body that happens to compress to its own size would be read back as uncompressed
+        if KTSR_HEADER_SIZE + body.len() as u32 == decomp_size {
+            return Err(std::io::Error::other(Error::CompressedSizeCollision { size: decomp_size }.to_string()));
+        }
+
+        let header = Ktsr {
+            decomp_size,
+            comp_size: KTSR_HEADER_SIZE + body.len() as u32,
+            .. self.header.clone()
+        };
+
+        header.write_options(writer, options)?;
+        body.write_options(writer, options)
     }
-}
\ No newline at end of file
+}
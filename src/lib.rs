@@ -0,0 +1,234 @@
+//! Library to manipulate KTSL (Koei Tecmo Sound Library) files.
+//! Ktsl2asbin and Ktsl2stbin files are both read and written through [`Ktsl`], with their content exposed as [`Section`]s.
+
+pub mod compression;
+pub mod dsp;
+pub mod error;
+pub use error::{Error, Result};
+
+pub mod ogg;
+pub mod opus;
+pub mod wav;
+
+pub mod ktsl;
+pub use ktsl::{ExportFormat, Filetype, Game, Ktsl, Ktsr, Platform, Section, SectionSummary};
+
+pub mod sections;
+pub use sections::*;
+
+#[cfg(test)]
+mod tests {
+    use binread::{io::Cursor, BinRead};
+    use binwrite::BinWrite;
+
+    use super::*;
+
+    fn padding_section(size: u32) -> Section {
+        let mut bytes = vec![];
+        bytes.extend(&0xA8DB7261u32.to_le_bytes());
+        bytes.extend(&size.to_le_bytes());
+        bytes.resize(size as usize, 0);
+
+        Section::read(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        let mut ktsl = Ktsl::new_asbin();
+        ktsl.entries = vec![padding_section(0x40), padding_section(0x80)];
+        ktsl.header.decomp_size = 0x100;
+        ktsl.compressed = true;
+
+        let mut bytes = vec![];
+        ktsl.write(&mut bytes).unwrap();
+
+        let read = Ktsl::read(&mut Cursor::new(&bytes)).unwrap();
+        assert!(read.compressed);
+        assert_eq!(read.header.flags, ktsl.header.flags);
+        assert!(read.header.comp_size < read.header.decomp_size);
+        assert_eq!(read.header.comp_size as usize, bytes.len());
+        assert_eq!(read.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_unknown_section_error() {
+        let mut bytes = vec![];
+        Ktsl::new_asbin().write(&mut bytes).unwrap();
+        bytes.extend(&0x12345678u32.to_le_bytes());
+        bytes.resize(0x80, 0);
+        // decomp_size and comp_size
+        bytes[0x18..0x1C].copy_from_slice(&0x80u32.to_le_bytes());
+        bytes[0x1C..0x20].copy_from_slice(&0x80u32.to_le_bytes());
+
+        match Ktsl::read(&mut Cursor::new(&bytes)).map_err(Error::from) {
+            Err(Error::UnknownSection { magic: 0x12345678, offset: 0x40 }) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_raw_section_roundtrip() {
+        let mut bytes = vec![];
+        Ktsl::new_asbin().write(&mut bytes).unwrap();
+        bytes.extend(&0x12345678u32.to_le_bytes());
+        bytes.extend(&0x10u32.to_le_bytes());
+        bytes.extend(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        bytes[0x18..0x1C].copy_from_slice(&0x50u32.to_le_bytes());
+        bytes[0x1C..0x20].copy_from_slice(&0x50u32.to_le_bytes());
+
+        let ktsl = Ktsl::read(&mut Cursor::new(&bytes)).unwrap();
+        match &ktsl.entries[..] {
+            [Section::Raw(raw)] => assert_eq!(raw.magic, 0x12345678),
+            other => panic!("Unexpected sections: {:?}", other),
+        }
+
+        let mut written = vec![];
+        ktsl.write(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    fn info_section(subsection_magic: u32, size: u32) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(&0x368C88BDu32.to_le_bytes());
+        bytes.extend(&size.to_le_bytes());
+        bytes.extend(&0xCAFEu32.to_le_bytes());
+        bytes.resize(0x18, 0);
+        bytes.extend(&subsection_magic.to_le_bytes());
+        bytes.resize(size as usize, 0xFF);
+        bytes
+    }
+
+    #[test]
+    fn test_info_subsections() {
+        for (magic, size) in [(0xB7DB4B73u32, 0x40u32), (0x241318, 0x30), (0x14AB5, 0x24), (0x12345678, 0x1C)].iter() {
+            let bytes = info_section(*magic, *size);
+
+            match Section::read(&mut Cursor::new(&bytes)).unwrap() {
+                Section::Info(info) => {
+                    assert_eq!(info.link_id, 0xCAFE);
+                    assert_eq!(info.subsection.magic(), *magic);
+                    assert_eq!(matches!(info.subsection, InfoSubsection::Unk3(_)), *magic == 0xB7DB4B73);
+                    assert_eq!(matches!(info.subsection, InfoSubsection::Unknown(_)), *magic == 0x12345678);
+
+                    let mut written = vec![];
+                    Section::Info(info).write(&mut written).unwrap();
+                    assert_eq!(written, bytes);
+                },
+                other => panic!("Unexpected section: {:?}", other),
+            }
+        }
+
+        // Too small for its subsection, which is an error rather than a section to skip
+        let mut bytes = info_section(0xB7DB4B73, 0x20);
+        bytes.extend(info_section(0xB7DB4B73, 0x40));
+        assert!(Section::read(&mut Cursor::new(&bytes)).is_err());
+    }
+
+    #[test]
+    fn test_compressed_size_collision() {
+        // Incompressible bytes followed by a run of zeroes, until the deflated body is exactly as big as the original
+        let noise = (0..0x200u32).scan(1u32, |state, _| {
+            *state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            Some((*state >> 16) as u8)
+        });
+        let mut data: Vec<u8> = noise.collect();
+
+        let ktsl = loop {
+            let mut bytes = vec![];
+            bytes.extend(&0x12345678u32.to_le_bytes());
+            bytes.extend(&(8 + data.len() as u32).to_le_bytes());
+            bytes.extend(&data);
+
+            let mut ktsl = Ktsl::new_stbin();
+            ktsl.compressed = true;
+            ktsl.entries = vec![Section::read(&mut Cursor::new(&bytes)).unwrap()];
+
+            if crate::compression::compress(&bytes).unwrap().len() == bytes.len() {
+                break ktsl;
+            }
+
+            assert!(data.len() < 0x1000, "No size collision found");
+            data.push(0);
+        };
+
+        assert!(ktsl.write(&mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_undersized_sections() {
+        for (magic, size) in [(0xA8DB7261u32, 0x4u32), (0xF13BD2A9, 0x8)].iter() {
+            let mut bytes = vec![];
+            bytes.extend(&magic.to_le_bytes());
+            bytes.extend(&size.to_le_bytes());
+            bytes.resize(0x10, 0);
+
+            assert!(Section::read(&mut Cursor::new(&bytes)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_broken_known_section() {
+        // An entry whose KTSS is cut short
+        let mut ktsl = Ktsl::new_stbin();
+        ktsl.entries = vec![padding_section(0x40)];
+
+        let mut bytes = vec![];
+        ktsl.write(&mut bytes).unwrap();
+        bytes[0x40..0x44].copy_from_slice(&0x15F4D409u32.to_le_bytes());
+
+        assert!(Ktsl::read(&mut Cursor::new(&bytes)).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_rejected() {
+        let mut ktsl = Ktsl::new_stbin();
+        ktsl.entries = vec![padding_section(0x40)];
+
+        let mut bytes = vec![];
+        ktsl.write(&mut bytes).unwrap();
+        // A seed in the header means the body is encrypted
+        bytes[0x20] = 2;
+        bytes[0x21..0x23].copy_from_slice(&[0xDE, 0xAD]);
+
+        assert!(matches!(Ktsl::read(&mut Cursor::new(&bytes)).map_err(Error::from), Err(Error::Encrypted)));
+
+        ktsl.header.enc_seed = vec![0xDE, 0xAD];
+        assert!(ktsl.write(&mut vec![]).is_err());
+    }
+
+    #[test]
+    #[ignore = "requires a ktsl2stbin sample in the working directory"]
+    fn test() {
+        Ktsl::open("./0x272c6efb.file").unwrap();
+    }
+
+    #[test]
+    #[ignore = "requires a ktsl2stbin sample in the working directory"]
+    fn test_ktsl_stbin_parse() {
+        let ktsl = Ktsl::open("./BGM_DLC_EN.ktsl2stbin").unwrap();
+        assert_eq!(ktsl.header.filetype, Filetype::Stream);
+        assert!(!ktsl.get_music_sections().is_empty());
+    }
+
+    #[test]
+    #[ignore = "requires a ktsl2asbin sample in the working directory"]
+    fn test_ktsl_asbin_parse() {
+        let ktsl: Ktsl = Ktsl::open("./31011.ktsl2asbin").unwrap();
+        assert_eq!(ktsl.header.filetype, Filetype::Asset);
+        assert!(!ktsl.entries.is_empty());
+    }
+
+    #[test]
+    #[ignore = "requires a ktsl2asbin sample in the working directory"]
+    fn test_asbin() {
+        let mut ktsl: Ktsl = match Ktsl::open("./31011.ktsl2asbin") {
+            Ok(ktsl) => ktsl,
+            Err(err) => panic!("{}", err),
+        };
+
+        let companions = ktsl.get_companion_sections();
+
+        assert!(!companions.is_empty());
+        assert!(companions.iter().all(|companion| companion.ktss_size > 0));
+    }
+}
@@ -1,20 +1,10 @@
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use structopt::StructOpt;
 
-mod ktsl2stbin;
-use ktsl2stbin::Ktsl2stbin;
-
-mod ktsl2asbin;
-use ktsl2asbin::Ktsl2asbin;
-
-mod ktsl;
-pub use ktsl::Ktsl;
-
-mod sections;
-pub use sections::*;
-
-use binread::Error::*;
+use ktsl_tool::{dsp, error, wav::Wav, Error, ExportFormat, Filetype, Ktsl, Ktss, Payload, SectionSummary};
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -28,154 +18,476 @@ struct Args {
 
 #[derive(Debug, StructOpt)]
 enum Command {
-    /// Reserved
-    Extract,
-    /// Reserved
-    Inject,
+    /// Extracts specific entries from a KTSL archive using their link ID
+    Extract(Extract),
+    /// Replaces a single entry of a KTSL archive in place and updates the companion sections of its asbin
+    Inject(Inject),
+    /// Changes the loop points of a single entry in place and updates its companion section in the asbin
+    Loop(Loop),
     /// Unpacks a KTSL archive to a directory with the proper file hierarchy for repacking
     Unpack(Unpack),
     /// Packs a directory into a KTSL archive using directory names
     Pack(Pack),
-    /// Output relevant informations about a KTSL archive
+    /// Decodes a DSP KTSS file, or a DSP entry or embedded sound of a KTSL archive, to a WAV file
+    Decode(Decode),
+    /// Encodes a WAV file to a standalone DSP KTSS file, or to a sound embedded in a Ktsl2asbin
+    Encode(Encode),
+    /// Lists every section of a KTSL archive, along with the stream of each entry
     Print(Print),
+    /// Checks that a KTSL archive is written back exactly as it was read
+    VerifyRoundtrip(VerifyRoundtrip),
+    /// Checks the sample counts, frame counts and loops of every entry against their audio
+    Validate(Validate),
 }
 
 // TODO: Turn all the reused args into a separate struct?
 
 #[derive(Debug, StructOpt)]
 struct Print {
-    /// Decompressed the file
-    #[structopt(short = "gz", long = "gzip")]
-    gz: bool,
-    /// Path to the file to print
+    /// Output JSON instead of a table, for scripts
+    #[structopt(long)]
+    json: bool,
+    /// Path to the Ktsl2stbin or Ktsl2asbin to print
+    #[structopt(parse(from_os_str))]
+    path: PathBuf
+}
+
+#[derive(Debug, StructOpt)]
+struct Decode {
+    /// Path to the KTSS to decode, or to the Ktsl2stbin or Ktsl2asbin holding it with --link-id
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+    /// Where to write the WAV file. Defaults to the path of the KTSS with a .wav extension,
+    /// or to the link ID next to the archive.
+    #[structopt(parse(from_os_str))]
+    out: Option<PathBuf>,
+    /// Link ID of the entry or embedded sound to decode, in hexadecimal
+    #[structopt(long, parse(try_from_str = parse_link_id))]
+    link_id: Option<u32>,
+    /// How many more times to play the loop, which is otherwise only marked in the smpl chunk
+    #[structopt(long, default_value = "0")]
+    loops: usize,
+}
+
+#[derive(Debug, StructOpt)]
+struct Encode {
+    /// Path to the WAV file to encode. Loop points are taken from its smpl chunk, if any.
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+    /// Where to write the KTSS. Defaults to the path of the WAV file with a .ktss extension.
+    /// With --asbin, where to write the Ktsl2asbin, which is otherwise modified in place.
+    #[structopt(parse(from_os_str))]
+    out: Option<PathBuf>,
+    /// Add the sound to this Ktsl2asbin as an embedded sound, instead of writing a KTSS
+    #[structopt(long, parse(from_os_str), requires = "link-id")]
+    asbin: Option<PathBuf>,
+    /// Link ID of the embedded sound, in hexadecimal
+    #[structopt(long, parse(try_from_str = parse_link_id))]
+    link_id: Option<u32>,
+    /// Name of the embedded sound. Defaults to the name of the WAV file.
+    #[structopt(long)]
+    name: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+struct VerifyRoundtrip {
+    /// Path to the file to verify
+    #[structopt(parse(from_os_str))]
+    path: PathBuf
+}
+
+#[derive(Debug, StructOpt)]
+struct Validate {
+    /// Path to the Ktsl2stbin or standalone KTSS to check
     #[structopt(parse(from_os_str))]
     path: PathBuf
 }
 
 #[derive(Debug, StructOpt)]
 struct Pack {
-    /// Compress the file
+    /// Compress the packed files
     #[structopt(short = "gz", long = "gzip")]
     gz: bool,
-    /// Path to the directory to pack
+    /// Overwrite the input asbin if the output paths point to it
+    #[structopt(short, long)]
+    force: bool,
+    /// Fix sample counts, frame counts and loops that don't match the audio of the entries
+    #[structopt(long)]
+    recompute: bool,
+    /// Where to write the Ktsl2stbin. Defaults to the name of the input asbin, with a .ktsl2stbin extension.
+    #[structopt(long, parse(from_os_str))]
+    stbin_out: Option<PathBuf>,
+    /// Where to write the Ktsl2asbin. Defaults to the input asbin, which requires --force.
+    /// Without an input asbin, both files are named after the directory instead.
+    #[structopt(long, parse(from_os_str))]
+    asbin_out: Option<PathBuf>,
+    /// Path to the directory to pack, holding a KTSS, Ogg (Opus or Vorbis), WAV or AT9 file named after the link ID of each entry
     #[structopt(parse(from_os_str))]
     path: PathBuf,
+    /// Path to the Ktsl2asbin describing the entries to pack
     #[structopt(parse(from_os_str))]
     asbin_path: Option<PathBuf>
 }
 
+impl Pack {
+    fn stbin_out(&self) -> error::Result<PathBuf> {
+        match &self.stbin_out {
+            Some(stbin_out) => Ok(stbin_out.clone()),
+            None => self.default_output("ktsl2stbin"),
+        }
+    }
+
+    fn asbin_out(&self) -> error::Result<PathBuf> {
+        match &self.asbin_out {
+            Some(asbin_out) => Ok(asbin_out.clone()),
+            None => self.default_output("ktsl2asbin"),
+        }
+    }
+
+    /// The input asbin with its extension swapped, so both files keep the names the game expects
+    fn default_output(&self, extension: &str) -> error::Result<PathBuf> {
+        if let Some(asbin_path) = &self.asbin_path {
+            return Ok(asbin_path.with_extension(extension));
+        }
+
+        // Resolved first, so "." or ".." give the name of an actual directory
+        let dir = self.path.canonicalize().map_err(|_| Error::MissingInput(self.path.clone()))?;
+        let name = dir.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|| "out".to_string());
+
+        Ok(dir.with_file_name(format!("{}.{}", name, extension)))
+    }
+}
+
+fn filetype_name(filetype: Filetype) -> &'static str {
+    match filetype {
+        Filetype::Asset => "Ktsl2asbin",
+        Filetype::Stream => "Ktsl2stbin",
+    }
+}
+
+fn print_table(ktsl: &Ktsl) {
+    let header = &ktsl.header;
+    println!("{}, game {:?}, platform {:?}, compressed: {}, decompressed size 0x{:08x}, {} sections",
+        filetype_name(header.filetype), header.game, header.platform, ktsl.compressed, header.decomp_size, ktsl.entries.len());
+
+    println!("{:<10}  {:<8}  {:<9}  {:<8}  {:<8}  {:<14}  {:>2}  {:>6}  {:>9}  {:<17}  Entry", "Offset", "Size", "Type", "Magic", "Link ID", "Codec", "Ch", "Rate", "Duration", "Loop");
+
+    for summary in ktsl.summaries() {
+        let link_id = summary.link_id.map(|link_id| format!("{:08x}", link_id)).unwrap_or_else(|| "-".to_string());
+        let codec = summary.codec.clone().unwrap_or_else(|| "-".to_string());
+        let entry = summary.entry_location.map(|(offset, size)| format!("0x{:08x} (0x{:x})", offset, size)).unwrap_or_else(|| "-".to_string());
+
+        let (channels, rate, duration, loop_points) = match &summary.stream {
+            Some(stream) => (
+                stream.channel_count.to_string(),
+                stream.sample_rate.to_string(),
+                format!("{:.3}s", stream.duration()),
+                stream.loop_points.map(|(start, length)| format!("{}+{}", start, length)).unwrap_or_else(|| "none".to_string()),
+            ),
+            None => ("-".to_string(), "-".to_string(), "-".to_string(), "-".to_string()),
+        };
+
+        println!("0x{:08x}  0x{:06x}  {:<9}  {:08x}  {:<8}  {:<14}  {:>2}  {:>6}  {:>9}  {:<17}  {}",
+            summary.offset, summary.size, summary.kind, summary.magic, link_id, codec, channels, rate, duration, loop_points, entry);
+    }
+}
+
+/// Quote a string for JSON
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+fn json_or_null<T, F: FnOnce(T) -> String>(value: Option<T>, to_json: F) -> String {
+    value.map(to_json).unwrap_or_else(|| "null".to_string())
+}
+
+fn section_json(summary: &SectionSummary) -> String {
+    let stream = summary.stream.as_ref();
+
+    let fields = [
+        ("type", json_string(summary.kind)),
+        ("magic", json_string(&format!("0x{:08x}", summary.magic))),
+        ("offset", summary.offset.to_string()),
+        ("size", summary.size.to_string()),
+        ("link_id", json_or_null(summary.link_id, |link_id| json_string(&format!("0x{:08x}", link_id)))),
+        ("codec", json_or_null(summary.codec.as_deref(), json_string)),
+        ("channels", json_or_null(stream, |stream| stream.channel_count.to_string())),
+        ("sample_rate", json_or_null(stream, |stream| stream.sample_rate.to_string())),
+        ("sample_count", json_or_null(stream, |stream| stream.sample_count.to_string())),
+        ("duration", json_or_null(stream, |stream| format!("{:.6}", stream.duration()))),
+        ("loop", json_or_null(stream.and_then(|stream| stream.loop_points), |(start, length)| format!("{{\"start\":{},\"length\":{}}}", start, length))),
+        ("entry_offset", json_or_null(summary.entry_location, |(offset, _)| offset.to_string())),
+        ("entry_size", json_or_null(summary.entry_location, |(_, size)| size.to_string())),
+    ];
+
+    let fields: Vec<String> = fields.iter().map(|(name, value)| format!("{}:{}", json_string(name), value)).collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+/// The whole archive as JSON. Link IDs and magics are hexadecimal strings, offsets and sizes plain numbers.
+fn archive_json(ktsl: &Ktsl) -> String {
+    let header = &ktsl.header;
+    let sections: Vec<String> = ktsl.summaries().iter().map(section_json).collect();
+
+    format!("{{\"filetype\":{},\"game\":{},\"platform\":{},\"compressed\":{},\"decompressed_size\":{},\"sections\":[{}]}}",
+        json_string(filetype_name(header.filetype)), json_string(&format!("{:?}", header.game)), json_string(&format!("{:?}", header.platform)),
+        ktsl.compressed, header.decomp_size, sections.join(","))
+}
+
+/// Make a path absolute without requiring the file to exist, so outputs can be compared before they are written
+fn resolve(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    match (parent.canonicalize(), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Make sure an output is not going to replace the input unless explicitly requested
+fn check_output(output: &Path, input: Option<&PathBuf>, force: bool) -> error::Result<()> {
+    if let Some(input) = input {
+        if resolve(output) == resolve(input) && !force {
+            return Err(Error::WouldOverwriteInput(output.to_path_buf()));
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+struct Extract {
+    /// Path to the file to extract from (Ktsl2stbin only)
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+    /// Link IDs of the entries to extract, in hexadecimal
+    #[structopt(parse(try_from_str = parse_link_id), required = true)]
+    link_ids: Vec<u32>,
+    /// Directory where the files are to be extracted. Defaults to "./out".
+    #[structopt(short, long, parse(from_os_str), default_value("./out"))]
+    out_dir: PathBuf,
+}
+
+fn parse_link_id(src: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(src.trim_start_matches("0x"), 16)
+}
+
+#[derive(Debug, StructOpt)]
+struct Inject {
+    /// Path to the Ktsl2stbin to modify
+    #[structopt(parse(from_os_str))]
+    stbin_path: PathBuf,
+    /// Path to the Ktsl2asbin describing the Ktsl2stbin
+    #[structopt(parse(from_os_str))]
+    asbin_path: PathBuf,
+    /// Link ID of the entry to replace, in hexadecimal
+    #[structopt(parse(try_from_str = parse_link_id))]
+    link_id: u32,
+    /// Path to the KTSS, Ogg (Opus or Vorbis), WAV or AT9 file to inject
+    #[structopt(parse(from_os_str))]
+    ktss_path: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct Loop {
+    /// Path to the Ktsl2stbin to modify
+    #[structopt(parse(from_os_str))]
+    stbin_path: PathBuf,
+    /// Path to the Ktsl2asbin describing the Ktsl2stbin
+    #[structopt(parse(from_os_str))]
+    asbin_path: PathBuf,
+    /// Link ID of the entry to modify, in hexadecimal
+    #[structopt(parse(try_from_str = parse_link_id))]
+    link_id: u32,
+    /// First sample of the loop
+    #[structopt(long, required_unless = "none", requires = "length")]
+    start: Option<u32>,
+    /// Length of the loop, in samples
+    #[structopt(long, required_unless = "none", requires = "start")]
+    length: Option<u32>,
+    /// Remove the loop entirely
+    #[structopt(long, conflicts_with_all = &["start", "length"])]
+    none: bool,
+}
+
 #[derive(Debug, StructOpt)]
 struct Unpack {
-    /// Decompress the file
-    #[structopt(short = "gz", long = "gzip")]
-    gz: bool,
     /// Path to the file to unpack (Ktsl2stbin only)
     #[structopt(parse(from_os_str))]
     path: PathBuf,
     /// Directory where the files are to be extracted. Defaults to "./out".
     #[structopt(parse(from_os_str), default_value("./out"))]
     out_dir: PathBuf,
+    /// Format to write the entries in, either "ktss", "ogg" (Opus entries only) or "wav" (DSP entries only).
+    /// Other entries are written in their own format: .ktss, .ogg for KOVS and .at9 for RIFF.
+    #[structopt(long, default_value("ktss"))]
+    format: ExportFormat,
 }
 
 fn main() {
     let opt = Args::from_args();
 
-    match opt.cmd {
+    if let Err(err) = run(opt.cmd) {
+        eprintln!("Error: {}", err);
+        std::process::exit(err.exit_code());
+    }
+}
+
+fn run(cmd: Command) -> error::Result<()> {
+    match cmd {
         Command::Print(args) => {
-            let ktsl = match Ktsl2stbin::open(&args.path) {
-                Ok(content) => content,
-                // TODO: Handle this better
-                Err(_) => panic!("Error while trying to open {}", &args.path.display()),
+            let ktsl = Ktsl::open(&args.path)?;
+
+            if args.json {
+                println!("{}", archive_json(&ktsl));
+            } else {
+                print_table(&ktsl);
+            }
+        },
+        Command::Decode(args) => {
+            let (ktss, default_out) = match args.link_id {
+                Some(link_id) => (Ktsl::open(&args.path)?.get_ktss(link_id)?, args.path.with_file_name(format!("{:08X}.wav", link_id))),
+                None => (Ktss::open(&args.path)?, args.path.with_extension("wav")),
             };
+            let out = args.out.clone().unwrap_or(default_out);
 
-            println!("Game ID: {}\nCompressed: {}\nDecompressed size: 0x{:08x}\nKTSL count: {}", ktsl.header.game_id, ktsl.header.comp_size != ktsl.header.decomp_size,ktsl.header.decomp_size, ktsl.entries.len());
+            let mut writer = std::io::BufWriter::new(std::fs::File::create(&out)?);
+            dsp::write_wav(&ktss, args.loops, &mut writer)?;
+            writer.flush()?;
         },
-        Command::Unpack(args) => {
-            let ktsl = match Ktsl2stbin::open(&args.path) {
-                Ok(content) => content,
-                // TODO: Handle this better
-                Err(_) => panic!("Error while trying to open {}", &args.path.display()),
+        Command::Encode(args) => {
+            let wav = Wav::read(std::io::BufReader::new(Error::open(&args.path)?))?;
+            let ktss = dsp::encode_ktss(&wav);
+
+            if let (Some(asbin_path), Some(link_id)) = (&args.asbin, args.link_id) {
+                let mut asbin = Ktsl::open(asbin_path)?;
+                let name = args.name.clone().unwrap_or_else(|| args.path.file_stem().unwrap_or_default().to_string_lossy().into_owned());
+
+                asbin.add_sound(link_id, &name, &ktss)?;
+                return asbin.save(args.out.as_ref().unwrap_or(asbin_path));
+            }
+
+            let out = args.out.clone().unwrap_or_else(|| args.path.with_extension("ktss"));
+
+            let mut writer = std::io::BufWriter::new(std::fs::File::create(&out)?);
+            binwrite::BinWrite::write(&ktss, &mut writer)?;
+            writer.flush()?;
+        },
+        Command::VerifyRoundtrip(args) => {
+            let mut original = vec![];
+            Error::open(&args.path)?.read_to_end(&mut original)?;
+
+            Ktsl::verify_roundtrip(&original)?;
+
+            println!("{} round-trips without any change", args.path.display());
+        },
+        Command::Validate(args) => {
+            let is_ktss = args.path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ktss"));
+
+            let issues = if is_ktss {
+                Ktss::open(&args.path)?.validate()?.into_iter().map(|issue| issue.to_string()).collect()
+            } else {
+                Ktsl::open(&args.path)?.validate()?.into_iter().map(|(link_id, issue)| format!("Entry {:08x}: {}", link_id, issue)).collect::<Vec<_>>()
             };
 
+            for issue in &issues {
+                println!("{}", issue);
+            }
+
+            if !issues.is_empty() {
+                return Err(Error::ValidationFailed { issue_count: issues.len() });
+            }
+
+            println!("{} is consistent with its audio", args.path.display());
+        },
+        Command::Unpack(args) => {
+            let ktsl = Ktsl::open(&args.path)?;
+
             // Create directory and childs just in case
-            std::fs::create_dir_all(&args.out_dir).unwrap();
+            std::fs::create_dir_all(&args.out_dir)?;
 
             // Unpack KTSR content in there
-            ktsl.unpack(&args.out_dir);
+            ktsl.unpack(&args.out_dir, args.format)?;
         },
-        Command::Pack(args) => {
-            let mut ktsl = Ktsl2stbin::new();
-            // TODO: Ask for GameID or figure it out somehow
-            //ktsl.pack(&args.path);
-
-            // Extreme grossness
-            let asbin = match args.asbin_path {
-                Some(asbin_path) => {
-                    let asbin = match Ktsl2asbin::open(&asbin_path) {
-                        // 'Sup Devin?
-                        Ok(rooster) => {
-                            Some(Box::new(rooster))
-                        },
-                        Err(err) => {
-                            println!("It means me no managed to parse asbin properly");
-                            match err {
-                                binread::Error::EnumErrors { pos, variant_errors } => panic!("Pos: {}\nErrors: {:?}", pos, variant_errors),
-                                _ => panic!(),
-                            }
-                            None
-                        },
-                    };
-                    asbin
-                },
-                None => None,
-            };
-            
+        Command::Extract(args) => {
+            let ktsl = Ktsl::open(&args.path)?;
 
-            ktsl.pack(&args.path, asbin);
+            std::fs::create_dir_all(&args.out_dir)?;
+
+            ktsl.extract(&args.link_ids, &args.out_dir)?;
         },
-        _ => { println!("Unimplemented"); },
-    }
-}
+        Command::Inject(args) => {
+            let mut stbin = Ktsl::open(&args.stbin_path)?;
+            let mut asbin = Ktsl::open(&args.asbin_path)?;
+            let payload = Payload::from_file(&args.ktss_path)?;
 
-mod tests {
-    use core::panic;
+            stbin.inject(args.link_id, payload, &mut asbin)?;
 
-    use super::*;
-    
-    #[test]
-    fn test() {
-        let ktsl: Ktsl2stbin = Ktsl2stbin::open("./0x272c6efb.file").unwrap();
-    }
+            Ktsl::save_pair(&stbin, &args.stbin_path, &asbin, &args.asbin_path)?;
+        },
+        Command::Loop(args) => {
+            let mut stbin = Ktsl::open(&args.stbin_path)?;
+            let mut asbin = Ktsl::open(&args.asbin_path)?;
 
-    #[test]
-    fn test_ktsl_stbin_parse() {
-        let ktsl: Ktsl = Ktsl::open("./BGM_DLC_EN.ktsl2stbin").unwrap();
-    }
+            let loop_points = if args.none { None } else { args.start.zip(args.length) };
+            stbin.set_loop(args.link_id, loop_points, &mut asbin)?;
 
-    #[test]
-    fn test_ktsl_asbin_parse() {
-        let ktsl: Ktsl = Ktsl::open("./31011.ktsl2asbin").unwrap();
-        dbg!(ktsl);
-    }
+            Ktsl::save_pair(&stbin, &args.stbin_path, &asbin, &args.asbin_path)?;
+        },
+        Command::Pack(args) => {
+            let stbin_out = args.stbin_out()?;
+            let asbin_out = args.asbin_out()?;
 
-    #[test]
-    fn test_asbin() {
-        let mut ktsl: Ktsl2asbin = match Ktsl2asbin::open("./31011.ktsl2asbin") {
-            Ok(ktsl) => ktsl,
-            Err(err) => match err {
-                binread::Error::EnumErrors { pos, variant_errors } => panic!("Pos: {}\nErrors: {:?}", pos, variant_errors),
-                _ => panic!(),
-            },
-        };
+            if resolve(&stbin_out) == resolve(&asbin_out) {
+                return Err(Error::SameOutput(stbin_out));
+            }
 
-        let test: Vec<&mut ktsl2asbin::Section> = ktsl.entries.iter_mut().filter_map(|section| {
-            if let ktsl2asbin::Section::Adpcm(_) = section {
-                return Some(section)
+            check_output(&stbin_out, args.asbin_path.as_ref(), args.force)?;
+            check_output(&asbin_out, args.asbin_path.as_ref(), args.force)?;
+
+            let mut ktsl = Ktsl::new_stbin();
+            // TODO: Ask for GameID or figure it out somehow
+
+            let mut asbin = match &args.asbin_path {
+                Some(asbin_path) => Ktsl::open(asbin_path)?,
+                None => Ktsl::new_asbin(),
+            };
+
+            let started = Instant::now();
+            let issues = ktsl.pack(&args.path, &mut asbin, args.recompute)?;
+
+            for (link_id, issue) in issues {
+                println!("Entry {:08x}: {}, {}", link_id, if args.recompute { "fixed" } else { "warning" }, issue);
             }
 
-            None
-        }).collect();
-        
-        dbg!(test.len());
+            // Compressed asbins stay compressed, but the flag can force it for both files
+            ktsl.compressed = args.gz;
+            asbin.compressed |= args.gz;
+
+            Ktsl::save_pair(&ktsl, &stbin_out, &asbin, &asbin_out)?;
+
+            println!("Packed {} entries in {:.2}s", ktsl.entries.len(), started.elapsed().as_secs_f64());
+        },
     }
+
+    Ok(())
 }
@@ -0,0 +1,205 @@
+//! Minimal Ogg container support, enough to carry a single logical stream in and out of KTSS files.
+
+use std::io::{self, Write};
+
+use crate::error::{Error, Result};
+
+pub const CAPTURE_PATTERN: &[u8; 4] = b"OggS";
+
+/// First page of a logical stream
+pub const FLAG_BOS: u8 = 0x2;
+/// Last page of a logical stream
+pub const FLAG_EOS: u8 = 0x4;
+
+const MAX_SEGMENTS: usize = 0xFF;
+
+fn crc32(data: &[u8]) -> u32 {
+    // Same polynomial as the usual CRC32, but without reflection or final XOR
+    data.iter().fold(0u32, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u32) << 24), |crc, _| {
+            if crc & 0x80000000 != 0 { (crc << 1) ^ 0x04C11DB7 } else { crc << 1 }
+        })
+    })
+}
+
+/// Number of lacing values a packet needs, including the terminating one
+fn segment_count(packet: &[u8]) -> usize {
+    packet.len() / 0xFF + 1
+}
+
+/// Whether the packets can fit in a single page
+pub fn fits_in_page(packets: &[&[u8]]) -> bool {
+    packets.iter().map(|packet| segment_count(packet)).sum::<usize>() <= MAX_SEGMENTS
+}
+
+/// Writes complete packets of a single logical stream, one page at a time
+pub struct OggWriter<W: Write> {
+    writer: W,
+    serial: u32,
+    sequence: u32,
+}
+
+impl<W: Write> OggWriter<W> {
+    pub fn new(writer: W, serial: u32) -> Self {
+        OggWriter {
+            writer,
+            serial,
+            sequence: 0,
+        }
+    }
+
+    /// Write a page made of complete packets. The granule position is the one at the end of the last packet.
+    pub fn write_page(&mut self, packets: &[&[u8]], granule: u64, flags: u8) -> io::Result<()> {
+        if !fits_in_page(packets) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Too many packets for a single Ogg page"));
+        }
+
+        let mut lacing = vec![];
+
+        for packet in packets {
+            lacing.extend(std::iter::repeat_n(0xFFu8, packet.len() / 0xFF));
+            lacing.push((packet.len() % 0xFF) as u8);
+        }
+
+        let mut page = vec![];
+        page.extend(CAPTURE_PATTERN);
+        page.push(0);
+        page.push(flags);
+        page.extend(&granule.to_le_bytes());
+        page.extend(&self.serial.to_le_bytes());
+        page.extend(&self.sequence.to_le_bytes());
+        // CRC, filled once the page is complete
+        page.extend(&[0; 4]);
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+
+        for packet in packets {
+            page.extend(*packet);
+        }
+
+        let crc = crc32(&page);
+        page[0x16..0x1A].copy_from_slice(&crc.to_le_bytes());
+
+        self.sequence += 1;
+        self.writer.write_all(&page)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Packets of the first logical stream found in an Ogg file
+#[derive(Debug, Default)]
+pub struct OggStream {
+    pub serial: u32,
+    pub packets: Vec<Vec<u8>>,
+    /// Granule position of the last page
+    pub granule: u64,
+}
+
+/// Read every packet of the first logical stream. Pages of other streams are skipped.
+pub fn read_stream(data: &[u8]) -> Result<OggStream> {
+    let mut stream = OggStream::default();
+    let mut pending: Vec<u8> = vec![];
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let header = data.get(pos..pos + 27).ok_or(Error::SizeMismatch { what: "Ogg page", expected: 27, found: (data.len() - pos) as u64 })?;
+
+        if &header[..4] != CAPTURE_PATTERN {
+            return Err(Error::BadMagic { pos: pos as u64 });
+        }
+
+        let segment_count = header[26] as usize;
+        let lacing = data.get(pos + 27..pos + 27 + segment_count).ok_or(Error::SizeMismatch { what: "Ogg page", expected: segment_count as u64, found: 0 })?;
+        let body_size: usize = lacing.iter().map(|value| *value as usize).sum();
+        let page_size = 27 + segment_count + body_size;
+
+        let page = data.get(pos..pos + page_size).ok_or(Error::SizeMismatch { what: "Ogg page", expected: page_size as u64, found: (data.len() - pos) as u64 })?;
+
+        let mut unchecked = page.to_vec();
+        unchecked[0x16..0x1A].copy_from_slice(&[0; 4]);
+
+        if crc32(&unchecked).to_le_bytes() != page[0x16..0x1A] {
+            return Err(Error::BadChecksum { pos: pos as u64 });
+        }
+
+        let serial = u32::from_le_bytes([page[0xE], page[0xF], page[0x10], page[0x11]]);
+
+        if pos == 0 {
+            stream.serial = serial;
+        }
+
+        if serial == stream.serial {
+            let mut body = &page[27 + segment_count..];
+
+            for value in lacing {
+                pending.extend(&body[..*value as usize]);
+                body = &body[*value as usize..];
+
+                // A lacing value under 0xFF ends the packet, otherwise it goes on in the next segment
+                if *value < 0xFF {
+                    stream.packets.push(std::mem::take(&mut pending));
+                }
+            }
+
+            let mut granule = [0; 8];
+            granule.copy_from_slice(&page[6..14]);
+            let granule = u64::from_le_bytes(granule);
+
+            // Pages where no packet ends have a granule position of -1
+            if granule != u64::MAX {
+                stream.granule = granule;
+            }
+        }
+
+        pos += page_size;
+    }
+
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_crc() {
+        // Reference value for the Ogg CRC of "123456789"
+        assert_eq!(crc32(b"123456789"), 0x89A1897F);
+    }
+
+    #[test]
+    fn test_page_layout() {
+        let mut writer = OggWriter::new(vec![], 0x1234);
+        let big = vec![0x55u8; 0x1FE];
+        writer.write_page(&[b"abc", &big], 960, FLAG_BOS).unwrap();
+
+        let page = writer.into_inner();
+        assert_eq!(&page[..4], b"OggS");
+        assert_eq!(page[5], FLAG_BOS);
+        assert_eq!(u64::from_le_bytes(page[6..14].try_into().unwrap()), 960);
+        assert_eq!(page[26], 4);
+        assert_eq!(&page[27..31], &[3, 0xFF, 0xFF, 0]);
+        assert_eq!(page.len(), 31 + 3 + 0x1FE);
+    }
+
+    #[test]
+    fn test_read_stream() {
+        let mut writer = OggWriter::new(vec![], 7);
+        let big = vec![0x55u8; 0x1FE];
+        writer.write_page(&[b"abc", &big], 960, FLAG_BOS).unwrap();
+        writer.write_page(&[b"d"], 1920, FLAG_EOS).unwrap();
+
+        let mut data = writer.into_inner();
+        let stream = read_stream(&data).unwrap();
+        assert_eq!(stream.serial, 7);
+        assert_eq!(stream.packets, vec![b"abc".to_vec(), big, b"d".to_vec()]);
+        assert_eq!(stream.granule, 1920);
+
+        data[32] ^= 0xFF;
+        assert!(matches!(read_stream(&data), Err(Error::BadChecksum { pos: 0 })));
+    }
+}
@@ -0,0 +1,451 @@
+//! Conversion between the Opus streams stored in KTSS files and standard Ogg Opus files.
+//! The packets are moved around as they are, nothing gets re-encoded.
+
+use std::io::{Read, Write};
+
+use crate::error::{Error, Result};
+use crate::ogg::{self, fits_in_page, OggWriter, FLAG_BOS, FLAG_EOS};
+use crate::sections::{frame_desc_size, Ktss, KtssBody, LopusPacket, OpusBody};
+
+/// Ogg Opus granule positions are always expressed at 48kHz
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+
+/// Roughly how many samples go in a single Ogg page, to keep seeking reasonable
+const SAMPLES_PER_PAGE: u64 = OPUS_SAMPLE_RATE as u64;
+
+const VENDOR: &str = concat!("ktsl_tool ", env!("CARGO_PKG_VERSION"));
+
+/// Longest duration a single Opus packet can have, 120ms
+const MAX_PACKET_SAMPLES: u32 = 5760;
+
+/// Number of samples (at 48kHz) in an Opus packet, according to its TOC byte. None if the packet is invalid.
+/// For multistream packets, the first stream is enough since they all share the same duration.
+pub fn packet_samples(packet: &[u8]) -> Option<u32> {
+    let toc = *packet.first()?;
+    let config = toc >> 3;
+
+    let frame_size = match config {
+        // SILK
+        0..=11 => [480, 960, 1920, 2880][config as usize % 4],
+        // Hybrid
+        12..=15 => [480, 960][config as usize % 2],
+        // CELT
+        _ => [120, 240, 480, 960][config as usize % 4],
+    };
+
+    let frame_count = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0x3F) as u32,
+    };
+
+    let samples = frame_size * frame_count;
+
+    if samples == 0 || samples > MAX_PACKET_SAMPLES {
+        return None;
+    }
+
+    Some(samples)
+}
+
+/// Number of samples (at 48kHz) the packets of a stream decode to, pre-skip included
+pub fn stream_samples(opus: &OpusBody) -> Result<u64> {
+    opus.audio.iter().enumerate().try_fold(0u64, |total, (i, packet)| {
+        Ok(total + packet_samples(&packet.content).ok_or(Error::BadOpusPacket { index: i })? as u64)
+    })
+}
+
+/// Identification header of an Ogg Opus stream
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpusHead {
+    pub channel_count: u8,
+    pub pre_skip: u16,
+    pub input_sample_rate: u32,
+    pub output_gain: i16,
+    pub mapping_family: u8,
+    pub stream_count: u8,
+    pub coupled_count: u8,
+    pub channel_mapping: Vec<u8>,
+}
+
+impl OpusHead {
+    /// The channel mapping of the KTSS is copied as is. With mapping family 1, players read the channels in
+    /// Vorbis order, so this assumes the games store them in that order too, which hasn't been checked.
+    pub fn from_ktss(ktss: &Ktss, opus: &OpusBody) -> Self {
+        // Family 0 only covers mono and stereo in a single stream, everything else needs an explicit mapping
+        let mapping_family = match ktss.channel_count {
+            1..=2 if opus.stream_count <= 1 => 0,
+            1..=8 => 1,
+            _ => 255,
+        };
+
+        OpusHead {
+            channel_count: ktss.channel_count,
+            pre_skip: opus.skip,
+            input_sample_rate: opus.orig_sample_rate,
+            output_gain: 0,
+            mapping_family,
+            stream_count: opus.stream_count,
+            coupled_count: opus.coupled_count,
+            channel_mapping: opus.channel_mapping.clone(),
+        }
+    }
+
+    /// Parse an identification header, as found in the first packet of an Ogg Opus stream
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < 19 || &packet[..8] != b"OpusHead" {
+            return None;
+        }
+
+        let channel_count = packet[9];
+        let mapping_family = packet[18];
+
+        let (stream_count, coupled_count, channel_mapping) = if mapping_family == 0 {
+            // Implicit mapping, only valid for mono and stereo
+            (1, channel_count.saturating_sub(1), (0..channel_count).collect())
+        } else {
+            let table = packet.get(19..21 + channel_count as usize)?;
+            (table[0], table[1], table[2..].to_vec())
+        };
+
+        Some(OpusHead {
+            channel_count,
+            pre_skip: u16::from_le_bytes([packet[10], packet[11]]),
+            input_sample_rate: u32::from_le_bytes([packet[12], packet[13], packet[14], packet[15]]),
+            output_gain: i16::from_le_bytes([packet[16], packet[17]]),
+            mapping_family,
+            stream_count,
+            coupled_count,
+            channel_mapping,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(b"OpusHead");
+        bytes.push(1);
+        bytes.push(self.channel_count);
+        bytes.extend(&self.pre_skip.to_le_bytes());
+        bytes.extend(&self.input_sample_rate.to_le_bytes());
+        bytes.extend(&self.output_gain.to_le_bytes());
+        bytes.push(self.mapping_family);
+
+        if self.mapping_family != 0 {
+            bytes.push(self.stream_count);
+            bytes.push(self.coupled_count);
+            bytes.extend(&self.channel_mapping);
+        }
+
+        bytes
+    }
+}
+
+/// Comment header of an Ogg Opus stream, with "KEY=value" comments
+fn opus_tags(comments: &[String]) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend(b"OpusTags");
+    bytes.extend(&(VENDOR.len() as u32).to_le_bytes());
+    bytes.extend(VENDOR.as_bytes());
+    bytes.extend(&(comments.len() as u32).to_le_bytes());
+
+    for comment in comments {
+        bytes.extend(&(comment.len() as u32).to_le_bytes());
+        bytes.extend(comment.as_bytes());
+    }
+
+    bytes
+}
+
+/// Read the comments of an OpusTags packet as (key, value) pairs, with the keys in uppercase
+fn parse_opus_tags(packet: &[u8]) -> Option<Vec<(String, String)>> {
+    fn read_u32(packet: &[u8], pos: usize) -> Option<usize> {
+        let bytes = packet.get(pos..pos + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    if packet.get(..8)? != b"OpusTags" {
+        return None;
+    }
+
+    let mut pos = 12 + read_u32(packet, 8)?;
+    let count = read_u32(packet, pos)?;
+    pos += 4;
+
+    let mut comments = vec![];
+
+    for _ in 0..count {
+        let len = read_u32(packet, pos)?;
+        let comment = String::from_utf8_lossy(packet.get(pos + 4..pos + 4 + len)?);
+        pos += 4 + len;
+
+        if let Some((key, value)) = comment.split_once('=') {
+            comments.push((key.to_ascii_uppercase(), value.to_string()));
+        }
+    }
+
+    Some(comments)
+}
+
+/// Convert a sample count of the KTSS to its equivalent at 48kHz
+fn to_opus_samples(ktss: &Ktss, samples: u64) -> u64 {
+    samples * OPUS_SAMPLE_RATE as u64 / ktss.sample_rate.max(1) as u64
+}
+
+/// Loop points as understood by most players, in samples from the start of the audible stream
+fn loop_comments(ktss: &Ktss) -> Vec<String> {
+    if ktss.loop_length == 0 || ktss.loop_start < 0 {
+        return vec![];
+    }
+
+    vec![
+        format!("LOOPSTART={}", to_opus_samples(ktss, ktss.loop_start as u64)),
+        format!("LOOPLENGTH={}", to_opus_samples(ktss, ktss.loop_length as u64)),
+    ]
+}
+
+/// Loop start and length from the comments, with LOOPEND accepted in place of LOOPLENGTH
+fn loop_from_comments(comments: &[(String, String)]) -> Option<(i32, u32)> {
+    let find = |key: &str| comments.iter().find(|(other, _)| other == key).and_then(|(_, value)| value.trim().parse::<u32>().ok());
+
+    let start = find("LOOPSTART")?;
+    let length = match find("LOOPLENGTH") {
+        Some(length) => length,
+        None => find("LOOPEND")?.checked_sub(start)?,
+    };
+
+    Some((start as i32, length))
+}
+
+/// Remux the Opus stream of a KTSS into an Ogg Opus file
+pub fn write_ogg_opus<W: Write>(ktss: &Ktss, serial: u32, writer: W) -> Result<()> {
+    let opus = match &ktss.body {
+        KtssBody::Opus(opus) => opus,
+        _ => return Err(Error::UnsupportedCodec { codec: ktss.codec.into() }),
+    };
+
+    let mut ogg = OggWriter::new(writer, serial);
+
+    ogg.write_page(&[&OpusHead::from_ktss(ktss, opus).to_bytes()], 0, FLAG_BOS)?;
+    ogg.write_page(&[&opus_tags(&loop_comments(ktss))], 0, 0)?;
+
+    // The last granule position tells players where to stop, past the padding of the last packet
+    let end = opus.skip as u64 + to_opus_samples(ktss, ktss.sample_count as u64);
+
+    let mut granule = 0u64;
+    let mut page: Vec<&[u8]> = vec![];
+    let mut page_start = 0u64;
+
+    for (i, packet) in opus.audio.iter().enumerate() {
+        let content = packet.content.as_slice();
+
+        let mut next = page.clone();
+        next.push(content);
+
+        if !page.is_empty() && (!fits_in_page(&next) || granule - page_start >= SAMPLES_PER_PAGE) {
+            ogg.write_page(&page, granule, 0)?;
+            page.clear();
+            page_start = granule;
+        }
+
+        let samples = packet_samples(content).ok_or(Error::BadOpusPacket { index: i })?;
+        granule += samples as u64;
+        page.push(content);
+    }
+
+    // A sample count shorter than the stream can't move the end before a page that was already written
+    ogg.write_page(&page, granule.min(end).max(page_start), FLAG_EOS)?;
+
+    Ok(())
+}
+
+/// Build a KTSS out of an Ogg Opus file. The packets are stored as they are, without re-encoding.
+pub fn read_ogg_opus<R: Read>(mut reader: R) -> Result<Ktss> {
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+
+    let stream = ogg::read_stream(&data)?;
+
+    // The first packet is the OpusHead, the second the OpusTags, and only then comes the audio
+    let head = stream.packets.first().and_then(|packet| OpusHead::parse(packet)).ok_or(Error::BadMagic { pos: 0 })?;
+    let comments = stream.packets.get(1).and_then(|packet| parse_opus_tags(packet)).unwrap_or_default();
+    let audio = stream.packets.get(2..).unwrap_or_default();
+
+    let mut total = 0u64;
+
+    for (i, packet) in audio.iter().enumerate() {
+        total += packet_samples(packet).ok_or(Error::BadOpusPacket { index: i })? as u64;
+    }
+
+    // The granule position of the last page trims the padding of the last packet. Without it, use the full length.
+    let end = if stream.granule > 0 { stream.granule.min(total) } else { total };
+
+    let mut opus = OpusBody::default();
+    opus.orig_sample_rate = head.input_sample_rate;
+    opus.skip = head.pre_skip;
+    opus.stream_count = head.stream_count;
+    opus.coupled_count = head.coupled_count;
+    opus.channel_mapping = head.channel_mapping;
+    opus.audio = audio.iter().map(|packet| LopusPacket {
+        size: packet.len() as u32,
+        unk: 0,
+        content: packet.clone(),
+    }).collect();
+
+    // Packets of a single size can be described by the header alone, otherwise a table of their sizes is needed
+    let sizes: Vec<u16> = opus.audio.iter().map(frame_desc_size).collect();
+
+    match sizes.first() {
+        Some(&size) if sizes.iter().all(|&other| other == size) => opus.frame_size = size,
+        _ => {
+            opus.frame_size = 0;
+            opus.frame_desc = Some(sizes);
+        },
+    }
+
+    let mut ktss = Ktss::new(KtssBody::Opus(opus));
+    ktss.channel_count = head.channel_count;
+    ktss.sample_rate = OPUS_SAMPLE_RATE;
+    ktss.sample_count = end.saturating_sub(head.pre_skip as u64) as u32;
+
+    if let Some((loop_start, loop_length)) = loop_from_comments(&comments) {
+        ktss.loop_start = loop_start;
+        ktss.loop_length = loop_length;
+    }
+
+    ktss.recompute_layout();
+
+    Ok(ktss)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn opus_body(ktss: &Ktss) -> &OpusBody {
+        match &ktss.body {
+            KtssBody::Opus(opus) => opus,
+            body => panic!("Expected an Opus body, got {:?}", body),
+        }
+    }
+
+    #[test]
+    fn test_packet_samples() {
+        // CELT 20ms, single frame
+        assert_eq!(packet_samples(&[0xF8]), Some(960));
+        // SILK 60ms, two frames
+        assert_eq!(packet_samples(&[0x19]), Some(5760));
+        // CELT 2.5ms, arbitrary number of frames
+        assert_eq!(packet_samples(&[0x83, 0x05]), Some(600));
+        assert_eq!(packet_samples(&[]), None);
+        // No frames at all, then more than 120ms
+        assert_eq!(packet_samples(&[0x83, 0x00]), None);
+        assert_eq!(packet_samples(&[0x1B, 0x03]), None);
+    }
+
+    #[test]
+    fn test_granule_positions() {
+        let mut opus = OpusBody::default();
+        opus.orig_sample_rate = 48000;
+        opus.skip = 312;
+        opus.stream_count = 1;
+        opus.coupled_count = 1;
+        opus.channel_mapping = vec![0, 1];
+        opus.audio = vec![LopusPacket { size: 2, unk: 0, content: vec![0xFC, 0x00] }; 2];
+
+        let mut ktss = Ktss::new(KtssBody::Opus(opus));
+        ktss.channel_count = 2;
+        ktss.sample_rate = 48000;
+        ktss.sample_count = 1500;
+        ktss.loop_start = 200;
+        ktss.loop_length = 1000;
+
+        let mut ogg = vec![];
+        write_ogg_opus(&ktss, 0, &mut ogg).unwrap();
+
+        let pages: Vec<usize> = (0..ogg.len() - 4).filter(|&i| &ogg[i..i + 4] == b"OggS").collect();
+        assert_eq!(pages.len(), 3);
+
+        let head = &ogg[pages[0] + 28..pages[1]];
+        assert_eq!(&head[..8], b"OpusHead");
+        assert_eq!(u16::from_le_bytes([head[10], head[11]]), 312);
+        // Mapping family 0, so no mapping table
+        assert_eq!(head.len(), 19);
+
+        let last = pages[2];
+        assert_eq!(ogg[last + 5], FLAG_EOS);
+        assert_eq!(u64::from_le_bytes(ogg[last + 6..last + 14].try_into().unwrap()), 312 + 1500);
+
+        let imported = read_ogg_opus(ogg.as_slice()).unwrap();
+        assert_eq!(imported.sample_count, 1500);
+        assert_eq!((imported.loop_start, imported.loop_length), (200, 1000));
+
+        let opus = opus_body(&imported);
+        assert_eq!(opus.skip, 312);
+        assert_eq!(opus.coupled_count, 1);
+        assert_eq!(opus.channel_mapping, vec![0, 1]);
+        assert_eq!(opus.frame_count, 2);
+        assert_eq!(opus.frame_size, 10);
+        assert_eq!(opus.audio[1].content, vec![0xFC, 0x00]);
+    }
+
+    #[test]
+    fn test_short_sample_count() {
+        let mut opus = OpusBody::default();
+        opus.orig_sample_rate = 48000;
+        opus.stream_count = 1;
+        opus.channel_mapping = vec![0];
+        opus.audio = vec![LopusPacket { size: 1, unk: 0, content: vec![0xFC] }; 120];
+
+        let mut ktss = Ktss::new(KtssBody::Opus(opus));
+        ktss.channel_count = 1;
+        ktss.sample_rate = 48000;
+        ktss.sample_count = 100;
+
+        let mut ogg = vec![];
+        write_ogg_opus(&ktss, 0, &mut ogg).unwrap();
+
+        let pages: Vec<usize> = (0..ogg.len() - 4).filter(|&i| &ogg[i..i + 4] == b"OggS").collect();
+        let granules: Vec<u64> = pages.iter().map(|&i| u64::from_le_bytes(ogg[i + 6..i + 14].try_into().unwrap())).collect();
+        assert!(granules.len() > 3);
+        assert!(granules.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", granules);
+        assert_eq!(granules[granules.len() - 1], granules[granules.len() - 2]);
+    }
+
+    #[test]
+    fn test_multistream_import() {
+        let head = OpusHead {
+            channel_count: 4,
+            pre_skip: 0,
+            input_sample_rate: 44100,
+            output_gain: 0,
+            mapping_family: 1,
+            stream_count: 2,
+            coupled_count: 2,
+            channel_mapping: vec![0, 1, 2, 3],
+        };
+        assert_eq!(OpusHead::parse(&head.to_bytes()), Some(head.clone()));
+
+        let mut writer = OggWriter::new(vec![], 1);
+        writer.write_page(&[&head.to_bytes()], 0, FLAG_BOS).unwrap();
+        writer.write_page(&[&opus_tags(&["loopstart=100".to_string(), "LOOPEND=1000".to_string()])], 0, 0).unwrap();
+        writer.write_page(&[&[0xF8, 1], &[0xF8, 1, 2]], 1920, FLAG_EOS).unwrap();
+
+        let ktss = read_ogg_opus(writer.into_inner().as_slice()).unwrap();
+        assert_eq!(ktss.sample_count, 1920);
+        assert_eq!((ktss.loop_start, ktss.loop_length), (100, 900));
+
+        let opus = opus_body(&ktss);
+        assert_eq!(opus.stream_count, 2);
+        assert_eq!(opus.orig_sample_rate, 44100);
+        assert_eq!(opus.frame_size, 0);
+        assert_eq!(opus.frame_desc, Some(vec![10, 11]));
+
+        let mut bytes = vec![];
+        binwrite::BinWrite::write(&ktss, &mut bytes).unwrap();
+        assert_eq!(bytes.len() as u32, ktss.section_size);
+
+        let reread = <Ktss as binread::BinRead>::read(&mut binread::io::Cursor::new(bytes)).unwrap();
+        assert_eq!(opus_body(&reread).audio[1].content, vec![0xF8, 1, 2]);
+    }
+}
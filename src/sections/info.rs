@@ -1,34 +1,84 @@
+use std::io::{Result, Write};
+
 use binread::{
     BinRead,
 };
 
 use binwrite::{
     BinWrite,
+    WriterOption,
 };
 
-#[derive(BinRead, Debug, Clone)]
+// Most of it is absolutely incorrect
+#[derive(BinRead, BinWrite, Debug, Clone)]
 #[br(little)]
 pub struct InfoSection {
-    // Seems related to what subsection_magic is used?
     pub section_size: u32,
     pub link_id: u32,
     channel_count: u16,
     layer_count: u16,
     padding_1: u32,
     cancel: u32,
+    // Every 0x368C88BD section shares the header above, what follows depends on the magic of the subsection
+    #[br(assert(section_size >= INFO_HEADER_SIZE + subsection.size()))]
     pub subsection: InfoSubsection,
+    #[br(count = section_size - INFO_HEADER_SIZE - subsection.size())]
+    unk: Vec<u8>,
 }
 
+/// Size of the header of the section, including the section and subsection magics
+const INFO_HEADER_SIZE: u32 = 0x1C;
+
 #[derive(BinRead, Debug, Clone)]
 #[br(little)]
 pub enum InfoSubsection {
-    // #[br(magic = 0x241318u32)]
-    // Unk1(Unk1InfoSubsection),
+    #[br(magic = 0x241318u32)]
+    Unk1(UnkInfo2Subsection),
     // Voice groups?
-    // #[br(magic = 0x14AB5u32)]
-    // Unk2(Unk1InfoSubsection),
+    #[br(magic = 0x14AB5u32)]
+    Unk2(UnkInfo2Subsection),
     #[br(magic = 0xB7DB4B73u32)]
     Unk3(UnkInfo1Subsection),
+    // Subsections we don't know the layout of yet, the content is kept in the section
+    Unknown(u32),
+}
+
+impl InfoSubsection {
+    pub fn magic(&self) -> u32 {
+        match self {
+            InfoSubsection::Unk1(_) => 0x241318,
+            InfoSubsection::Unk2(_) => 0x14AB5,
+            InfoSubsection::Unk3(_) => 0xB7DB4B73,
+            InfoSubsection::Unknown(magic) => *magic,
+        }
+    }
+
+    /// Size of the parsed part of the subsection, not counting the magic
+    pub fn size(&self) -> u32 {
+        match self {
+            InfoSubsection::Unk1(_) | InfoSubsection::Unk2(_) => 0x8,
+            InfoSubsection::Unk3(_) => 0x1C,
+            InfoSubsection::Unknown(_) => 0,
+        }
+    }
+}
+
+impl BinWrite for InfoSubsection {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> Result<()> {
+        match self {
+            InfoSubsection::Unk1(unk) | InfoSubsection::Unk2(unk) => (self.magic(), unk).write_options(writer, options),
+            InfoSubsection::Unk3(unk) => (self.magic(), unk).write_options(writer, options),
+            InfoSubsection::Unknown(magic) => magic.write_options(writer, options),
+        }
+    }
+}
+
+// Only the first two words are read, the rest of the layout is unknown and stays in the section
+#[derive(BinRead, BinWrite, Debug, Default, Clone)]
+#[br(little)]
+pub struct UnkInfo2Subsection {
+    pub unk1: u32,
+    pub unk2: u32,
 }
 
 #[derive(BinRead, BinWrite, Debug, Default, Clone)]
@@ -44,24 +94,3 @@ pub struct UnkInfo1Subsection {
     // Padding until the first offset?
     pub unk5: u32,
 }
-
-#[derive(BinRead, BinWrite, Debug, Default, Clone)]
-#[br(little)]
-pub struct UnkInfo1Subsubsection {
-    // 0x4820efc4
-    pub section_magic: u32,
-    pub section_size: u32,
-    // Not sure
-    pub link_id: u32,
-    pub unk1: u32,
-    pub entry_offset_count: u32,
-    // Relative to section_magic
-    pub entry_offset_section_offset: u32,
-    // Honestly not sure here, number of float seems to match with second u8?
-    pub some_magic: u32,
-    #[br(count = 0x19)]
-    pub some_section: Vec<f32>,
-    #[br(count = entry_offset_count)]
-    pub entry_offsets: Vec<u32>
-
-}
\ No newline at end of file
@@ -0,0 +1,168 @@
+use binread::BinRead;
+use binwrite::BinWrite;
+
+use crate::error::{Error, Result};
+use crate::ogg;
+
+/// "KOVS"
+pub const KOVS_MAGIC: u32 = 0x53564F4B;
+pub const KOVS_HEADER_SIZE: u32 = 0x20;
+
+/// Only the start of the Ogg data is obfuscated
+const OBFUSCATED_SIZE: usize = 0x100;
+
+/// Ogg Vorbis stream with a small header, used on PC. The start of the Ogg data is obfuscated.
+#[derive(BinRead, BinWrite, Debug, Default, Clone)]
+pub struct Kovs {
+    pub magic: u32,
+    pub data_size: u32,
+    /// Loop start in samples, 0 when the stream doesn't loop
+    pub loop_start: u32,
+    unknown: [u32; 5],
+    /// The Ogg data, as stored
+    #[br(count = data_size)]
+    data: Vec<u8>,
+}
+
+/// The first bytes are XORed with their own offset, which also works the other way around
+fn xor_start(data: &mut [u8]) {
+    for (i, byte) in data.iter_mut().take(OBFUSCATED_SIZE).enumerate() {
+        *byte ^= i as u8;
+    }
+}
+
+/// What is needed from the identification header of a Vorbis stream
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct VorbisInfo {
+    pub channel_count: u8,
+    pub sample_rate: u32,
+    pub sample_count: u32,
+}
+
+impl Kovs {
+    /// Wrap a plain Ogg Vorbis file
+    pub fn from_ogg(ogg: &[u8]) -> Result<Self> {
+        let mut kovs = Kovs {
+            magic: KOVS_MAGIC,
+            ..Default::default()
+        };
+        kovs.set_ogg(ogg)?;
+
+        // Loop points are usually kept as comments by audio editors
+        kovs.loop_start = ogg::read_stream(ogg)?.packets.get(1).and_then(|packet| vorbis_comment(packet, "LOOPSTART")).unwrap_or(0);
+
+        Ok(kovs)
+    }
+
+    /// The plain Ogg Vorbis file
+    pub fn to_ogg(&self) -> Vec<u8> {
+        let mut ogg = self.data.clone();
+        xor_start(&mut ogg);
+        ogg
+    }
+
+    /// Replace the stream with a plain Ogg Vorbis file, making sure it really is one
+    pub fn set_ogg(&mut self, ogg: &[u8]) -> Result<()> {
+        vorbis_info(ogg)?;
+
+        self.data = ogg.to_vec();
+        xor_start(&mut self.data);
+        self.data_size = self.data.len() as u32;
+
+        Ok(())
+    }
+
+    pub fn info(&self) -> Result<VorbisInfo> {
+        vorbis_info(&self.to_ogg())
+    }
+
+    pub fn size(&self) -> u32 {
+        KOVS_HEADER_SIZE + self.data.len() as u32
+    }
+}
+
+/// Whether an Ogg file holds a Vorbis stream, going by its first packet
+pub fn is_vorbis(ogg: &[u8]) -> bool {
+    ogg::read_stream(ogg).map(|stream| stream.packets.first().is_some_and(|packet| packet.starts_with(b"\x01vorbis"))).unwrap_or(false)
+}
+
+fn vorbis_info(ogg: &[u8]) -> Result<VorbisInfo> {
+    let stream = ogg::read_stream(ogg)?;
+
+    match stream.packets.first() {
+        Some(packet) if packet.len() >= 16 && packet.starts_with(b"\x01vorbis") => Ok(VorbisInfo {
+            channel_count: packet[11],
+            sample_rate: u32::from_le_bytes([packet[12], packet[13], packet[14], packet[15]]),
+            sample_count: stream.granule as u32,
+        }),
+        _ => Err(Error::BadMagic { pos: 0 }),
+    }
+}
+
+/// Value of a numeric comment in a Vorbis comment header
+fn vorbis_comment(packet: &[u8], key: &str) -> Option<u32> {
+    let read_u32 = |pos: usize| packet.get(pos..pos + 4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize);
+
+    if !packet.starts_with(b"\x03vorbis") {
+        return None;
+    }
+
+    let mut pos = 11 + read_u32(7)?;
+    let count = read_u32(pos)?;
+    pos += 4;
+
+    for _ in 0..count {
+        let len = read_u32(pos)?;
+        let comment = String::from_utf8_lossy(packet.get(pos + 4..pos + 4 + len)?);
+        pos += 4 + len;
+
+        if let Some((name, value)) = comment.split_once('=') {
+            if name.eq_ignore_ascii_case(key) {
+                return value.trim().parse().ok();
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ogg::{OggWriter, FLAG_BOS, FLAG_EOS};
+
+    fn vorbis_ogg() -> Vec<u8> {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend(&0u32.to_le_bytes());
+        ident.push(2);
+        ident.extend(&44100u32.to_le_bytes());
+        ident.extend(&[0; 13]);
+
+        let mut comments = b"\x03vorbis".to_vec();
+        comments.extend(&0u32.to_le_bytes());
+        comments.extend(&1u32.to_le_bytes());
+        comments.extend(&13u32.to_le_bytes());
+        comments.extend(b"LOOPSTART=441");
+
+        let mut writer = OggWriter::new(vec![], 1);
+        writer.write_page(&[&ident], 0, FLAG_BOS).unwrap();
+        writer.write_page(&[&comments, &[0x05; 0x120]], 0, 0).unwrap();
+        writer.write_page(&[&[0; 0x10]], 88200, FLAG_EOS).unwrap();
+        writer.into_inner()
+    }
+
+    #[test]
+    fn test_obfuscation() {
+        let ogg = vorbis_ogg();
+        let kovs = Kovs::from_ogg(&ogg).unwrap();
+
+        assert_eq!(kovs.loop_start, 441);
+        assert_eq!(kovs.size(), KOVS_HEADER_SIZE + ogg.len() as u32);
+        assert_ne!(&kovs.data[..4], b"OggS");
+        assert_eq!(&kovs.data[OBFUSCATED_SIZE..], &ogg[OBFUSCATED_SIZE..]);
+        assert_eq!(kovs.to_ogg(), ogg);
+
+        assert_eq!(kovs.info().unwrap(), VorbisInfo { channel_count: 2, sample_rate: 44100, sample_count: 88200 });
+        assert!(is_vorbis(&ogg));
+    }
+}
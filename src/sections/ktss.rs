@@ -0,0 +1,831 @@
+use std::{
+    fmt,
+    io::{BufReader, Result, Write},
+    path::Path
+};
+
+use binread::{
+    io::{Read, Seek, SeekFrom},
+    BinRead,
+    BinResult,
+    ReadOptions,
+};
+
+use binwrite::{
+    BinWrite,
+    WriterOption,
+};
+
+use crate::error::Error;
+
+/// "KTSS"
+pub const KTSS_MAGIC: u32 = 0x5353544B;
+
+/// Offsets in the KTSS header are relative to the start of the codec fields
+const KTSS_CODEC_FIELDS_START: u32 = 0x20;
+/// Size of the header shared by every codec, the body starts right after it
+const KTSS_COMMON_HEADER_SIZE: u32 = 0x40;
+/// Size of the Opus header fields, up to the channel mapping
+const OPUS_HEADER_SIZE: u32 = 0x1C;
+
+fn align(value: u32, alignment: u32) -> u32 {
+    value.div_ceil(alignment) * alignment
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum KtssCodec {
+    /// Nintendo DSP ADPCM, used by the older titles
+    Dsp,
+    /// Opus, as packed by Nintendo's libopus
+    #[default]
+    Opus,
+    Unknown(u8),
+}
+
+impl From<u8> for KtssCodec {
+    fn from(id: u8) -> Self {
+        match id {
+            0x2 => KtssCodec::Dsp,
+            0x9 => KtssCodec::Opus,
+            id => KtssCodec::Unknown(id),
+        }
+    }
+}
+
+impl From<KtssCodec> for u8 {
+    fn from(codec: KtssCodec) -> Self {
+        match codec {
+            KtssCodec::Dsp => 0x2,
+            KtssCodec::Opus => 0x9,
+            KtssCodec::Unknown(id) => id,
+        }
+    }
+}
+
+impl BinWrite for KtssCodec {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> Result<()> {
+        u8::from(*self).write_options(writer, options)
+    }
+}
+
+/// The offsets and sizes of the header are written back as they were read. After editing the body,
+/// `recompute_layout` derives them from it again.
+#[derive(BinRead, Debug, Default, Clone)]
+pub struct Ktss {
+    pub magic: u32,
+    pub section_size: u32,
+    unk0: [u8; 0x18],
+    #[br(map = |codec: u8| KtssCodec::from(codec))]
+    pub codec: KtssCodec,
+    unk1: u8,
+    /// Header version, which decides where the DSP channel parameters are
+    pub version: u8,
+    pub unk3: u8,
+    codec_start_offset: u32,
+    pub layer_count: u8,
+    pub channel_count: u8,
+    unk4: u16,
+    pub sample_rate: u32,
+    pub sample_count: u32,
+    pub loop_start: i32,
+    pub loop_length: u32,
+    padding: u32,
+    #[br(args(codec, version, channel_count, codec_start_offset, section_size))]
+    pub body: KtssBody,
+    /// Whatever follows the audio, up to the section size
+    #[br(count = section_size.saturating_sub(KTSS_COMMON_HEADER_SIZE + body.size()))]
+    trailing: Vec<u8>,
+}
+
+impl Ktss {
+    pub fn new(body: KtssBody) -> Self {
+        let mut ktss = Ktss {
+            magic: KTSS_MAGIC,
+            codec: body.codec().unwrap_or_default(),
+            layer_count: 1,
+            body,
+            .. Default::default()
+        };
+
+        ktss.recompute_layout();
+        ktss
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> crate::error::Result<Self> {
+        Ok(Self::read(&mut BufReader::new(Error::open(path)?))?)
+    }
+
+    /// Open either a KTSS, an Ogg Opus file or a WAV file to encode as DSP ADPCM, going by the extension
+    pub fn from_file<P: AsRef<Path>>(path: P) -> crate::error::Result<Self> {
+        let extension = path.as_ref().extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+
+        match extension.as_deref() {
+            Some("opus") | Some("ogg") => crate::opus::read_ogg_opus(Error::open(path)?),
+            Some("wav") => Ok(crate::dsp::encode_ktss(&crate::wav::Wav::read(BufReader::new(Error::open(path)?))?)),
+            _ => Self::open(path),
+        }
+    }
+
+    /// Where the audio starts and how big it is, going by the body
+    fn audio_layout(&self) -> (u32, u32) {
+        match &self.body {
+            KtssBody::Opus(opus) => {
+                let mut offset = opus_frame_desc_start(opus);
+
+                if opus_needs_frame_desc(opus) {
+                    offset = align(offset + 2 * opus.audio.len() as u32, 0x10);
+                }
+
+                (offset, opus.audio.iter().map(|packet| 8 + packet.content.len() as u32).sum())
+            },
+            KtssBody::Dsp(dsp) => (dsp_header_end(dsp) + dsp_padding(dsp) as u32, dsp.data.len() as u32),
+            KtssBody::Unknown(bytes) => (KTSS_COMMON_HEADER_SIZE, bytes.len() as u32),
+        }
+    }
+
+    /// Size of the KTSS once written
+    pub fn size(&self) -> u32 {
+        KTSS_COMMON_HEADER_SIZE + self.body.size() + self.trailing.len() as u32
+    }
+
+    /// Recompute the offsets and sizes stored in the header from the content that follows it.
+    /// Anything past the audio is dropped, as the section ends with it.
+    pub fn recompute_layout(&mut self) {
+        if let Some(codec) = self.body.codec() {
+            self.codec = codec;
+        }
+
+        let (audio_start, audio_size) = self.audio_layout();
+
+        // The body is read with one mapping entry or set of DSP parameters per channel
+        match &self.body {
+            KtssBody::Opus(opus) => self.channel_count = opus.channel_mapping.len() as u8,
+            KtssBody::Dsp(dsp) => {
+                self.channel_count = dsp.channels.len() as u8;
+                self.version = dsp.version;
+            },
+            KtssBody::Unknown(_) => (),
+        }
+
+        match &mut self.body {
+            KtssBody::Opus(opus) => {
+                if opus_needs_frame_desc(opus) {
+                    // The table is only read when there is no fixed frame size
+                    opus.frame_size = 0;
+                    opus.frame_desc_addr = opus_frame_desc_start(opus) - KTSS_CODEC_FIELDS_START;
+
+                    if !opus.frame_desc.as_ref().is_some_and(|frame_desc| frame_desc_matches(frame_desc, &opus.audio)) {
+                        opus.frame_desc = Some(opus.audio.iter().map(frame_desc_size).collect());
+                    }
+                } else {
+                    opus.frame_desc = None;
+                }
+
+                for packet in opus.audio.iter_mut() {
+                    packet.size = packet.content.len() as u32;
+                }
+
+                opus.frame_count = opus.audio.len() as u32;
+                opus.audio_section_addr = audio_start - KTSS_CODEC_FIELDS_START;
+                opus.audio_section_size = audio_size;
+            },
+            KtssBody::Dsp(dsp) => {
+                let (channels_start, stride) = dsp.layout();
+                dsp.header.resize((channels_start - KTSS_COMMON_HEADER_SIZE) as usize, 0);
+                dsp.channel_padding.resize(dsp.channels.len(), vec![]);
+                for padding in dsp.channel_padding.iter_mut() {
+                    padding.resize((stride - DSP_CHANNEL_SIZE) as usize, 0);
+                }
+
+                let padding = dsp_padding(dsp);
+                dsp.padding.resize(padding, 0);
+            },
+            KtssBody::Unknown(_) => (),
+        }
+
+        // Where the audio of an unknown body starts can't be told, so the offset read is kept
+        if !matches!(self.body, KtssBody::Unknown(_)) {
+            self.codec_start_offset = audio_start - KTSS_CODEC_FIELDS_START;
+        }
+        self.trailing.clear();
+        self.section_size = audio_start + audio_size;
+    }
+
+    /// Number of samples the audio really decodes to, padding of the last frame included. None for unknown codecs.
+    pub fn decoded_sample_count(&self) -> crate::error::Result<Option<u32>> {
+        Ok(match &self.body {
+            KtssBody::Opus(opus) => {
+                let samples = crate::opus::stream_samples(opus)?.saturating_sub(opus.skip as u64);
+                Some((samples * self.sample_rate as u64 / crate::opus::OPUS_SAMPLE_RATE as u64) as u32)
+            },
+            KtssBody::Dsp(dsp) => {
+                let frames = dsp.data.len() / DSP_FRAME_SIZE / dsp.channels.len().max(1);
+                Some((frames * DSP_SAMPLES_PER_FRAME) as u32)
+            },
+            KtssBody::Unknown(_) => None,
+        })
+    }
+
+    /// Check the header against the audio it describes
+    pub fn validate(&self) -> crate::error::Result<Vec<KtssIssue>> {
+        let mut issues = vec![];
+        let mut sample_count = self.sample_count;
+
+        if let Some(actual) = self.decoded_sample_count()? {
+            if self.sample_count > actual {
+                issues.push(KtssIssue::SampleCount { stored: self.sample_count, actual });
+                sample_count = actual;
+            }
+        }
+
+        if let KtssBody::Opus(opus) = &self.body {
+            if opus.frame_count as usize != opus.audio.len() {
+                issues.push(KtssIssue::FrameCount { stored: opus.frame_count, actual: opus.audio.len() as u32 });
+            }
+
+            if opus.frame_size == 0 {
+                if !opus.frame_desc.as_ref().is_some_and(|frame_desc| frame_desc_matches(frame_desc, &opus.audio)) {
+                    issues.push(KtssIssue::FrameDescMismatch);
+                }
+            } else if let Some((index, packet)) = opus.audio.iter().enumerate().find(|(_, packet)| 8 + packet.content.len() > opus.frame_size as usize) {
+                issues.push(KtssIssue::FrameTooLarge { index, size: 8 + packet.content.len() as u32, frame_size: opus.frame_size });
+            }
+        }
+
+        if self.loop_length > 0 && self.loop_start >= 0 && self.loop_start as u64 + self.loop_length as u64 > sample_count as u64 {
+            issues.push(KtssIssue::LoopOutOfBounds { start: self.loop_start, length: self.loop_length, sample_count });
+        }
+
+        Ok(issues)
+    }
+
+    /// Fix whatever `validate` finds, and return what was fixed.
+    /// Loops running past the end are cut short, or removed if they start past it.
+    pub fn recompute(&mut self) -> crate::error::Result<Vec<KtssIssue>> {
+        let issues = self.validate()?;
+
+        for issue in &issues {
+            match *issue {
+                KtssIssue::SampleCount { actual, .. } => self.sample_count = actual,
+                KtssIssue::FrameCount { .. } | KtssIssue::FrameDescMismatch | KtssIssue::FrameTooLarge { .. } => self.recompute_layout(),
+                KtssIssue::LoopOutOfBounds { start, sample_count, .. } if (start as u32) < sample_count => {
+                    self.loop_length = sample_count - start as u32;
+                },
+                KtssIssue::LoopOutOfBounds { .. } => {
+                    self.loop_start = 0;
+                    self.loop_length = 0;
+                },
+            }
+        }
+
+        Ok(issues)
+    }
+}
+
+impl BinWrite for Ktss {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> Result<()> {
+        (self.magic, self.section_size, &self.unk0[..]).write_options(writer, options)?;
+        (self.codec, self.unk1, self.version, self.unk3, self.codec_start_offset).write_options(writer, options)?;
+        (self.layer_count, self.channel_count, self.unk4, self.sample_rate, self.sample_count).write_options(writer, options)?;
+        (self.loop_start, self.loop_length, self.padding).write_options(writer, options)?;
+        (&self.body, &self.trailing).write_options(writer, options)
+    }
+}
+
+/// Where the frame size table goes, right after the channel mapping
+fn opus_frame_desc_start(opus: &OpusBody) -> u32 {
+    align(KTSS_COMMON_HEADER_SIZE + OPUS_HEADER_SIZE + opus.channel_mapping.len() as u32, 0x10) + 0x10
+}
+
+/// Whether the packets need a table of their sizes, because there is no fixed frame size or some packets don't fit in it
+fn opus_needs_frame_desc(opus: &OpusBody) -> bool {
+    opus.frame_size == 0 || opus.audio.iter().any(|packet| 8 + packet.content.len() > opus.frame_size as usize)
+}
+
+/// Size of a packet as listed in the frame size table, which counts its 8 bytes header like the fixed frame size does
+pub fn frame_desc_size(packet: &LopusPacket) -> u16 {
+    8 + packet.content.len() as u16
+}
+
+/// Whether a frame size table lists the size of every packet
+fn frame_desc_matches(frame_desc: &[u16], audio: &[LopusPacket]) -> bool {
+    frame_desc.len() == audio.len() && frame_desc.iter().zip(audio).all(|(&size, packet)| size == frame_desc_size(packet))
+}
+
+fn dsp_header_end(dsp: &DspBody) -> u32 {
+    let (channels_start, stride) = dsp.layout();
+    channels_start + stride * dsp.channels.len() as u32
+}
+
+/// Padding needed between the DSP channel parameters and the audio.
+/// What was read is kept as long as the audio stays aligned to 0x10, so existing files are written back as they were.
+fn dsp_padding(dsp: &DspBody) -> usize {
+    let header_end = dsp_header_end(dsp);
+
+    if (header_end + dsp.padding.len() as u32).is_multiple_of(0x10) {
+        dsp.padding.len()
+    } else {
+        (align(header_end, 0x10) - header_end) as usize
+    }
+}
+
+/// A value in a KTSS header that does not match the audio that follows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KtssIssue {
+    /// The header claims more samples than the audio decodes to
+    SampleCount { stored: u32, actual: u32 },
+    /// The frame count is not the number of packets
+    FrameCount { stored: u32, actual: u32 },
+    /// The frame size table does not list the size of every packet
+    FrameDescMismatch,
+    /// A packet does not fit in the fixed frame size, so a frame size table is needed
+    FrameTooLarge { index: usize, size: u32, frame_size: u16 },
+    /// The loop ends past the last sample
+    LoopOutOfBounds { start: i32, length: u32, sample_count: u32 },
+}
+
+impl fmt::Display for KtssIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KtssIssue::SampleCount { stored, actual } => write!(f, "sample count is {} but the audio only holds {}", stored, actual),
+            KtssIssue::FrameCount { stored, actual } => write!(f, "frame count is {} but there are {} packets", stored, actual),
+            KtssIssue::FrameDescMismatch => write!(f, "frame size table does not match the packets"),
+            KtssIssue::FrameTooLarge { index, size, frame_size } => write!(f, "packet {} is 0x{:x} bytes, more than the fixed frame size of 0x{:x}", index, size, frame_size),
+            KtssIssue::LoopOutOfBounds { start, length, sample_count } => write!(f, "loop from {} for {} samples ends past the last sample ({})", start, length, sample_count),
+        }
+    }
+}
+
+/// Everything following the common header, which depends on the codec
+#[derive(Debug, Clone)]
+pub enum KtssBody {
+    Dsp(DspBody),
+    Opus(OpusBody),
+    /// A codec we don't know of, kept as it is
+    Unknown(Vec<u8>),
+}
+
+impl KtssBody {
+    /// Size of the body once written, as it is laid out now
+    pub fn size(&self) -> u32 {
+        match self {
+            KtssBody::Dsp(dsp) => {
+                let channels: usize = dsp.channel_padding.iter().map(|padding| DSP_CHANNEL_SIZE as usize + padding.len()).sum();
+                (dsp.header.len() + channels + dsp.padding.len() + dsp.data.len()) as u32
+            },
+            KtssBody::Opus(opus) => {
+                let frame_desc = opus.frame_desc.as_ref().map(|frame_desc| align(2 * frame_desc.len() as u32, 0x10)).unwrap_or(0);
+                let audio: u32 = opus.audio.iter().map(|packet| 8 + packet.content.len() as u32).sum();
+                opus_frame_desc_start(opus) - KTSS_COMMON_HEADER_SIZE + frame_desc + audio
+            },
+            KtssBody::Unknown(bytes) => bytes.len() as u32,
+        }
+    }
+
+    /// The codec matching this body, if it is one we know of
+    pub fn codec(&self) -> Option<KtssCodec> {
+        match self {
+            KtssBody::Dsp(_) => Some(KtssCodec::Dsp),
+            KtssBody::Opus(_) => Some(KtssCodec::Opus),
+            KtssBody::Unknown(_) => None,
+        }
+    }
+}
+
+impl Default for KtssBody {
+    fn default() -> Self {
+        KtssBody::Opus(OpusBody::default())
+    }
+}
+
+impl BinRead for KtssBody {
+    type Args = (KtssCodec, u8, u8, u32, u32);
+
+    fn read_options<R: Read + Seek>(reader: &mut R, options: &ReadOptions, (codec, version, channel_count, codec_start_offset, section_size): Self::Args) -> BinResult<Self> {
+        Ok(match codec {
+            KtssCodec::Opus => KtssBody::Opus(OpusBody::read_options(reader, options, (channel_count,))?),
+            KtssCodec::Dsp if dsp_layout(version).is_some() => {
+                let audio_start = codec_start_offset + KTSS_CODEC_FIELDS_START;
+                let args = (version, channel_count, audio_start, section_size.saturating_sub(audio_start));
+                KtssBody::Dsp(DspBody::read_options(reader, options, args)?)
+            },
+            // DSP versions we don't know the layout of are kept as they are too, rather than misreading their parameters
+            KtssCodec::Dsp | KtssCodec::Unknown(_) => {
+                let mut bytes = vec![0u8; section_size.saturating_sub(KTSS_COMMON_HEADER_SIZE) as usize];
+                reader.read_exact(&mut bytes)?;
+                KtssBody::Unknown(bytes)
+            },
+        })
+    }
+}
+
+impl BinWrite for KtssBody {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> Result<()> {
+        match self {
+            KtssBody::Dsp(dsp) => dsp.write_options(writer, options),
+            KtssBody::Opus(opus) => opus.write_options(writer, options),
+            KtssBody::Unknown(bytes) => bytes.write_options(writer, options),
+        }
+    }
+}
+
+#[derive(BinRead, BinWrite, Debug, Default, Clone)]
+#[br(import(channel_count: u8))]
+pub struct OpusBody {
+    audio_section_addr: u32,
+    audio_section_size: u32,
+    pub frame_desc_addr: u32,
+    pub frame_count: u32,
+    /// Size every packet fits in, 8 bytes header included. 0 when their sizes are listed in `frame_desc` instead.
+    pub frame_size: u16,
+    some_constant: u16,
+    pub orig_sample_rate: u32,
+    pub skip: u16,
+    pub stream_count: u8,
+    pub coupled_count: u8,
+    #[br(count = channel_count, align_after(0x10), pad_after(0x10))]
+    #[binwrite(align_after(0x10), pad_after(0x10))]
+    pub channel_mapping: Vec<u8>,
+    /// Size of each packet, 8 bytes header included, for streams without a fixed frame size
+    #[br(if = frame_size == 0, count = frame_count, align_after(0x10))]
+    #[binwrite(with(write_optional_vec), align_after(0x10))]
+    pub frame_desc: Option<Vec<u16>>,
+    #[br(big, count = frame_count)]
+    #[binwrite(big)]
+    pub audio: Vec<LopusPacket>
+}
+
+pub fn write_optional_vec<W, T>(vec: &Option<Vec<T>>, writer: &mut W, options: &WriterOption) -> Result<()>
+    where W: Write,
+             T: BinWrite,
+{
+    match vec {
+        Some(inner) => BinWrite::write_options(inner, writer, options),
+        None => Ok(())
+    }
+}
+
+#[derive(BinRead, BinWrite, Debug, Default, Clone)]
+pub struct LopusPacket {
+    pub size: u32,
+    pub unk: u32,
+    #[br(count = size)]
+    pub content: Vec<u8>,
+}
+
+/// Size of the decoding parameters of a single DSP channel
+pub const DSP_CHANNEL_SIZE: u32 = 0x2E;
+/// Size of a DSP frame, holding 14 samples
+pub const DSP_FRAME_SIZE: usize = 0x8;
+pub const DSP_SAMPLES_PER_FRAME: usize = 14;
+
+/// Decoding parameters of a single DSP channel
+#[derive(BinRead, BinWrite, Debug, Default, Clone, PartialEq)]
+pub struct DspChannel {
+    pub coefficients: [i16; 16],
+    pub gain: u16,
+    pub predictor_scale: u16,
+    pub history: [i16; 2],
+    pub loop_predictor_scale: u16,
+    pub loop_history: [i16; 2],
+}
+
+/// Where the DSP channel parameters start and how far apart they are, for the header versions we know of
+fn dsp_layout(version: u8) -> Option<(u32, u32)> {
+    match version {
+        1 => Some((KTSS_COMMON_HEADER_SIZE, DSP_CHANNEL_SIZE)),
+        // Fire Emblem: Three Houses
+        3 => Some((0x5C, 0x60)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DspBody {
+    /// Header version this body was read with, see `dsp_layout`
+    version: u8,
+    /// Whatever is between the common header and the channel parameters
+    header: Vec<u8>,
+    pub channels: Vec<DspChannel>,
+    /// Whatever follows the parameters of each channel, up to the next one
+    channel_padding: Vec<Vec<u8>>,
+    /// Whatever is between the channel parameters and the audio
+    padding: Vec<u8>,
+    /// Frames of every channel, one after the other
+    pub data: Vec<u8>,
+}
+
+impl Default for DspBody {
+    fn default() -> Self {
+        DspBody::new(vec![], vec![])
+    }
+}
+
+impl BinRead for DspBody {
+    type Args = (u8, u8, u32, u32);
+
+    fn read_options<R: Read + Seek>(reader: &mut R, options: &ReadOptions, (version, channel_count, audio_start, audio_size): Self::Args) -> BinResult<Self> {
+        let (channels_start, stride) = match dsp_layout(version) {
+            Some(layout) => layout,
+            None => {
+                let pos = reader.seek(SeekFrom::Current(0))?;
+                return Err(Error::UnsupportedCodec { codec: KtssCodec::Dsp.into() }.into_binread(pos));
+            },
+        };
+
+        let mut header = vec![0u8; (channels_start - KTSS_COMMON_HEADER_SIZE) as usize];
+        reader.read_exact(&mut header)?;
+
+        let mut channels = Vec::with_capacity(channel_count as usize);
+        let mut channel_padding = Vec::with_capacity(channel_count as usize);
+
+        for _ in 0..channel_count {
+            channels.push(DspChannel::read_options(reader, options, ())?);
+
+            let mut padding = vec![0u8; (stride - DSP_CHANNEL_SIZE) as usize];
+            reader.read_exact(&mut padding)?;
+            channel_padding.push(padding);
+        }
+
+        let mut padding = vec![0u8; audio_start.saturating_sub(channels_start + stride * channel_count as u32) as usize];
+        reader.read_exact(&mut padding)?;
+        let mut data = vec![0u8; audio_size as usize];
+        reader.read_exact(&mut data)?;
+
+        Ok(DspBody { version, header, channels, channel_padding, padding, data })
+    }
+}
+
+impl BinWrite for DspBody {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> Result<()> {
+        self.header.write_options(writer, options)?;
+
+        for (channel, padding) in self.channels.iter().zip(&self.channel_padding) {
+            (channel, padding).write_options(writer, options)?;
+        }
+
+        (&self.padding, &self.data).write_options(writer, options)
+    }
+}
+
+impl DspBody {
+    /// A body laid out like the first version of the header, which is what older titles use
+    pub fn new(channels: Vec<DspChannel>, data: Vec<u8>) -> Self {
+        DspBody {
+            version: 1,
+            header: vec![],
+            channel_padding: vec![vec![]; channels.len()],
+            channels,
+            padding: vec![],
+            data,
+        }
+    }
+
+    /// Header version this body is laid out for
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Where the channel parameters start and how far apart they are
+    fn layout(&self) -> (u32, u32) {
+        dsp_layout(self.version).unwrap_or((KTSS_COMMON_HEADER_SIZE, DSP_CHANNEL_SIZE))
+    }
+
+    /// The frames of a single channel, in order
+    pub fn channel_frames(&self, channel: usize) -> impl Iterator<Item = &[u8]> {
+        self.data.chunks_exact(DSP_FRAME_SIZE).skip(channel).step_by(self.channels.len().max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use binread::io::Cursor;
+
+    use super::*;
+
+    fn reread(ktss: &Ktss) -> Ktss {
+        let mut bytes = vec![];
+        ktss.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len() as u32, ktss.size());
+
+        Ktss::read(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    #[test]
+    fn test_dsp_roundtrip() {
+        let channels = vec![
+            DspChannel { coefficients: [0x100; 16], gain: 0, predictor_scale: 0x17, ..Default::default() },
+            DspChannel { coefficients: [-0x100; 16], gain: 0, predictor_scale: 0x42, history: [1, -1], ..Default::default() },
+        ];
+        // Two frames for each channel, interleaved
+        let data: Vec<u8> = (0..4u8).flat_map(|frame| vec![frame; DSP_FRAME_SIZE]).collect();
+
+        let mut ktss = Ktss::new(KtssBody::Dsp(DspBody::new(channels.clone(), data)));
+        ktss.channel_count = 2;
+        ktss.sample_count = 2 * DSP_SAMPLES_PER_FRAME as u32;
+        ktss.recompute_layout();
+        assert_eq!(ktss.codec, KtssCodec::Dsp);
+
+        let reread = reread(&ktss);
+        assert_eq!(reread.codec, KtssCodec::Dsp);
+
+        match reread.body {
+            KtssBody::Dsp(dsp) => {
+                assert_eq!(dsp.channels, channels);
+                let frames: Vec<u8> = dsp.channel_frames(1).map(|frame| frame[0]).collect();
+                assert_eq!(frames, vec![1, 3]);
+            },
+            body => panic!("Expected a DSP body, got {:?}", body),
+        }
+    }
+
+    #[test]
+    fn test_dsp_versions() {
+        let channels = vec![
+            DspChannel { coefficients: [0x100; 16], predictor_scale: 0x17, ..Default::default() },
+            DspChannel { coefficients: [-0x100; 16], predictor_scale: 0x42, ..Default::default() },
+        ];
+        let dsp = DspBody { version: 3, ..DspBody::new(channels.clone(), vec![0x11; 0x20]) };
+
+        let mut bytes = vec![];
+        Ktss::new(KtssBody::Dsp(dsp)).write(&mut bytes).unwrap();
+        assert_eq!(bytes[0x22], 3);
+        // The parameters of the second channel are 0x60 bytes after the ones of the first
+        assert_eq!(&bytes[0x5C..0x5E], &0x100i16.to_le_bytes());
+        assert_eq!(&bytes[0xBC..0xBE], &(-0x100i16).to_le_bytes());
+
+        match Ktss::read(&mut Cursor::new(&bytes)).unwrap().body {
+            KtssBody::Dsp(dsp) => {
+                assert_eq!(dsp.version(), 3);
+                assert_eq!(dsp.channels, channels);
+                assert_eq!(dsp.data, vec![0x11; 0x20]);
+            },
+            body => panic!("Expected a DSP body, got {:?}", body),
+        }
+
+        // A version we don't know the layout of is kept as it is
+        bytes[0x22] = 2;
+        let ktss = Ktss::read(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(ktss.codec, KtssCodec::Dsp);
+        assert!(matches!(ktss.body, KtssBody::Unknown(_)));
+
+        let mut written = vec![];
+        ktss.write(&mut written).unwrap();
+        assert_eq!(written, bytes);
+    }
+
+    #[test]
+    fn test_unknown_codec_kept() {
+        let mut ktss = Ktss::new(KtssBody::Unknown(vec![0xAB; 0x30]));
+        ktss.codec = KtssCodec::Unknown(0x5);
+        ktss.recompute_layout();
+
+        let reread = reread(&ktss);
+        assert_eq!(reread.codec, KtssCodec::Unknown(0x5));
+        assert!(matches!(reread.body, KtssBody::Unknown(bytes) if bytes == vec![0xAB; 0x30]));
+    }
+
+    #[test]
+    fn test_header_kept_as_read() {
+        let ktss = Ktss::new(KtssBody::Opus(OpusBody {
+            channel_mapping: vec![0],
+            audio: vec![LopusPacket { size: 2, unk: 0, content: vec![0xF8, 0x00] }],
+            ..Default::default()
+        }));
+
+        let mut bytes = vec![];
+        ktss.write(&mut bytes).unwrap();
+        bytes[0x8..0x20].copy_from_slice(&[0x5A; 0x18]);
+        // Bytes past the audio, counted in the section size
+        bytes.extend(&[0xA5; 0x20]);
+        let section_size = bytes.len() as u32;
+        bytes[0x4..0x8].copy_from_slice(&section_size.to_le_bytes());
+
+        let mut read = Ktss::read(&mut Cursor::new(&bytes)).unwrap();
+        assert_eq!(read.size(), section_size);
+
+        let mut written = vec![];
+        read.write(&mut written).unwrap();
+        assert_eq!(written, bytes);
+
+        // Recomputing drops whatever follows the audio, and only that
+        read.recompute_layout();
+        written.clear();
+        read.write(&mut written).unwrap();
+        assert_eq!(written.len() as u32, section_size - 0x20);
+        assert_eq!(&written[0x8..0x20], &[0x5A; 0x18]);
+    }
+
+    #[test]
+    fn test_recompute_layout() {
+        let packet = |size: usize| LopusPacket { size: 0, unk: 0, content: vec![0xF8; size] };
+
+        let mut ktss = Ktss::new(KtssBody::Opus(OpusBody {
+            channel_mapping: vec![0, 1],
+            audio: vec![packet(0x20), packet(0x30)],
+            ..Default::default()
+        }));
+        ktss.channel_count = 2;
+
+        // The frame sizes vary so a table is needed
+        let written = reread(&ktss);
+        assert_eq!(written.section_size, ktss.size());
+
+        let opus = match &written.body {
+            KtssBody::Opus(opus) => opus,
+            body => panic!("Expected an Opus body, got {:?}", body),
+        };
+        assert_eq!(opus.frame_count, 2);
+        assert_eq!(opus.frame_desc, Some(vec![0x28, 0x38]));
+        assert_eq!(opus.audio_section_addr, written.codec_start_offset);
+        assert_eq!(opus.audio_section_size, 0x28 + 0x38);
+        assert_eq!(opus.audio[1].content.len(), 0x30);
+
+        // Editing the audio or the mapping afterwards only needs the layout to be recomputed
+        let mut edited = written.clone();
+        if let KtssBody::Opus(opus) = &mut edited.body {
+            opus.audio.push(packet(0x10));
+            opus.channel_mapping = vec![0, 1, 2, 3];
+        }
+        edited.recompute_layout();
+        let edited = reread(&edited);
+        assert!(matches!(&edited.body, KtssBody::Opus(opus) if opus.frame_count == 3 && opus.frame_desc.as_ref().is_some_and(|sizes| sizes.len() == 3)));
+        assert!(edited.section_size > written.section_size);
+        assert_eq!(edited.channel_count, 4);
+        assert!(matches!(&edited.body, KtssBody::Opus(opus) if opus.channel_mapping == vec![0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn test_variable_frame_size() {
+        let packet = |size: usize| LopusPacket { size: size as u32, unk: 0, content: vec![0xF8; size] };
+
+        let mut ktss = Ktss::new(KtssBody::Opus(OpusBody {
+            audio: vec![packet(0x10), packet(0x20)],
+            ..Default::default()
+        }));
+        ktss.sample_rate = 48000;
+
+        // A header made for packets that all fit in a fixed size
+        if let KtssBody::Opus(opus) = &mut ktss.body {
+            opus.frame_size = 0x18;
+            opus.frame_desc = None;
+        }
+        assert_eq!(ktss.validate().unwrap(), vec![KtssIssue::FrameTooLarge { index: 1, size: 0x28, frame_size: 0x18 }]);
+
+        // The packets don't fit in a fixed size anymore, so they get a table
+        ktss.recompute().unwrap();
+        let reread = reread(&ktss);
+        assert!(reread.validate().unwrap().is_empty());
+        assert!(matches!(&reread.body, KtssBody::Opus(opus) if opus.frame_size == 0 && opus.frame_desc == Some(vec![0x18, 0x28])));
+
+        // A table that lies about the packets is rebuilt, and so is one without the packet headers
+        let mut edited = reread.clone();
+        if let KtssBody::Opus(opus) = &mut edited.body {
+            opus.frame_desc = Some(vec![0x18, 0x18]);
+        }
+        assert_eq!(edited.validate().unwrap(), vec![KtssIssue::FrameDescMismatch]);
+
+        if let KtssBody::Opus(opus) = &mut edited.body {
+            opus.frame_desc = Some(vec![0x10, 0x20]);
+        }
+        assert_eq!(edited.validate().unwrap(), vec![KtssIssue::FrameDescMismatch]);
+        edited.recompute().unwrap();
+        assert!(edited.validate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recompute() {
+        let opus = OpusBody {
+            // Three CELT packets of 20ms each
+            audio: (0..3).map(|_| LopusPacket { size: 2, unk: 0, content: vec![0xF8, 0x00] }).collect(),
+            frame_size: 10,
+            skip: 80,
+            ..Default::default()
+        };
+
+        let mut ktss = Ktss::new(KtssBody::Opus(opus));
+        ktss.sample_rate = 48000;
+        ktss.sample_count = 5000;
+        if let KtssBody::Opus(opus) = &mut ktss.body {
+            opus.frame_count = 1;
+        }
+        ktss.loop_start = 2000;
+        ktss.loop_length = 2000;
+
+        assert_eq!(ktss.decoded_sample_count().unwrap(), Some(2800));
+        assert_eq!(ktss.validate().unwrap(), vec![
+            KtssIssue::SampleCount { stored: 5000, actual: 2800 },
+            KtssIssue::FrameCount { stored: 1, actual: 3 },
+            KtssIssue::LoopOutOfBounds { start: 2000, length: 2000, sample_count: 2800 },
+        ]);
+
+        assert_eq!(ktss.recompute().unwrap().len(), 3);
+        assert_eq!((ktss.sample_count, ktss.loop_start, ktss.loop_length), (2800, 2000, 800));
+        assert!(ktss.validate().unwrap().is_empty());
+
+        // A loop starting past the end can't be saved
+        ktss.loop_start = 3000;
+        ktss.recompute().unwrap();
+        assert_eq!((ktss.loop_start, ktss.loop_length), (0, 0));
+    }
+}
@@ -1,8 +1,19 @@
-/// You thought it'd be a module file, but it was I, Raytwo
-/// Jokes aside, all the section structs from ktsl.rs should be moved in their own file here, probably with a Trait to implement at least New.
+//! You thought it'd be a module file, but it was I, Raytwo
+//! Jokes aside, every section struct that can be found in a KTSR container lives in its own file here.
+
+use binread::{
+    io::{Read, Seek, SeekFrom},
+    BinResult, ReadOptions,
+};
 
 mod music;
 pub use music::*;
+mod ktss;
+pub use ktss::*;
+mod kovs;
+pub use kovs::*;
+mod riff;
+pub use riff::*;
 mod info;
 pub use info::*;
 mod padding;
@@ -10,4 +21,14 @@ pub use padding::*;
 mod sound;
 pub use sound::*;
 mod unknown;
-pub use unknown::*;
\ No newline at end of file
+pub use unknown::*;
+mod raw;
+pub use raw::*;
+
+/// Read the bytes up to the next multiple of `align` in the file, so they can be written back as they were instead of zeroes
+pub(crate) fn read_alignment<R: Read + Seek>(reader: &mut R, _options: &ReadOptions, (align,): (u64,)) -> BinResult<Vec<u8>> {
+    let pos = reader.seek(SeekFrom::Current(0))?;
+    let mut bytes = vec![0u8; ((align - (pos % align)) % align) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
@@ -1,31 +1,35 @@
 use std::{
     fs::File,
-    io::BufReader,
+    io::{BufWriter, Read, Result, Write},
     path::Path
 };
 
 use binread::{
+    io::{Seek, SeekFrom},
     BinRead,
     BinResult,
+    ReadOptions,
 };
 
 use binwrite::{
     BinWrite,
+    WriterOption,
 };
 
+use super::{Kovs, Ktss, KtssBody, KtssIssue, Riff, KOVS_MAGIC, RIFF_MAGIC};
+use crate::error::Error;
+
 pub const KTSL_HEADER_SIZE: u32 =  0x40;
 
 // Header for the container representing every single entry
-#[derive(BinRead, BinWrite, Debug, Default, Clone)]
+#[derive(BinRead, Debug, Default, Clone)]
 pub struct MusicSection {
     pub section_size: u32,
     pub link_id: u32,
     pub header_size: u32,
     pub ktss_size: u32,
-    #[br(align_before(0x40), align_after(header_size))]
-    #[binwrite(align_after(0x40))]
-    // TODO: Can also be a KOVS or RIFF (at9), change this to use a enum instead
-    pub ktss: Ktss,
+    #[br(align_before(0x40), align_after(0x40))]
+    pub payload: Payload,
 }
 
 impl MusicSection {
@@ -35,55 +39,287 @@ impl MusicSection {
             .. Default::default()
         }
     }
+
+    /// Wrap a KTSS in a new entry, with the section size aligned as expected by the game
+    pub fn from_ktss(link_id: u32, ktss: Ktss) -> Self {
+        Self::from_payload(link_id, Payload::Ktss(ktss))
+    }
+
+    /// Wrap any payload in a new entry, with the section size aligned as expected by the game
+    pub fn from_payload(link_id: u32, payload: Payload) -> Self {
+        let size = payload.size();
+
+        // Some align required, should probably be made into a preprocessor?
+        let section_size = if !(size + KTSL_HEADER_SIZE).is_multiple_of(0x40) {
+            size + KTSL_HEADER_SIZE + ( 0x40 - ((size + KTSL_HEADER_SIZE) % 0x40))
+        } else {
+            size + KTSL_HEADER_SIZE
+        };
+
+        MusicSection {
+            link_id,
+            section_size,
+            ktss_size: size,
+            payload,
+            .. MusicSection::new()
+        }
+    }
+
+    /// Where the payload is in the archive when the section starts at section_offset, which is what companion sections point to
+    pub fn ktss_offset(&self, section_offset: u32) -> u32 {
+        section_offset + self.header_size
+    }
+
+    fn create_export(&self, out_dir: &Path, extension: &str) -> Result<BufWriter<File>> {
+        let mut file_path = out_dir.to_path_buf();
+        file_path.push(format!("{:08X}.{}", self.link_id, extension));
+
+        Ok(BufWriter::new(File::create(&file_path)?))
+    }
+
+    /// Write the payload of this entry in the directory provided, named after its link ID.
+    /// KOVS are written as the plain Ogg Vorbis file they hold, RIFF as an AT9 file.
+    pub fn export(&self, out_dir: &Path) -> crate::error::Result<()> {
+        match &self.payload {
+            Payload::Ktss(ktss) => {
+                let mut writer = self.create_export(out_dir, "ktss")?;
+                ktss.write(&mut writer)?;
+                Ok(writer.flush()?)
+            },
+            Payload::Kovs(kovs) => {
+                let mut writer = self.create_export(out_dir, "ogg")?;
+                writer.write_all(&kovs.to_ogg())?;
+                Ok(writer.flush()?)
+            },
+            Payload::Riff(riff) => {
+                let mut writer = self.create_export(out_dir, "at9")?;
+                writer.write_all(&riff.to_bytes())?;
+                Ok(writer.flush()?)
+            },
+        }
+    }
+
+    /// Write the KTSS of this entry as an Ogg Opus file in the directory provided, named after its link ID.
+    /// Anything that isn't Opus is written in its own format instead.
+    pub fn export_ogg(&self, out_dir: &Path) -> crate::error::Result<()> {
+        match &self.payload {
+            Payload::Ktss(ktss) if matches!(ktss.body, KtssBody::Opus(_)) => {
+                let mut writer = self.create_export(out_dir, "opus")?;
+                crate::opus::write_ogg_opus(ktss, self.link_id, &mut writer)?;
+                Ok(writer.flush()?)
+            },
+            _ => self.export(out_dir),
+        }
+    }
+
+    /// Decode the KTSS of this entry to a WAV file in the directory provided, named after its link ID.
+    /// Anything that isn't DSP ADPCM is written in its own format instead.
+    pub fn export_wav(&self, out_dir: &Path) -> crate::error::Result<()> {
+        match &self.payload {
+            Payload::Ktss(ktss) if matches!(ktss.body, KtssBody::Dsp(_)) => {
+                let mut writer = self.create_export(out_dir, "wav")?;
+                crate::dsp::write_wav(ktss, 0, &mut writer)?;
+                Ok(writer.flush()?)
+            },
+            _ => self.export(out_dir),
+        }
+    }
 }
 
-#[derive(BinRead, BinWrite, Debug, Default, Clone)]
-pub struct Ktss {
-    pub magic: u32,
-    #[binwrite(align_after(0x20))]
-    pub section_size: u32,
-    #[br(align_before(0x20))]
-    pub codec: u8,
-    unk1: u8,
-    pub unk2: u8,
-    pub unk3: u8,
-    codec_start_offset: u32,
-    pub layer_count: u8,
+/// What the game needs to know about a stream, whatever its format
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StreamInfo {
     pub channel_count: u8,
-    unk4: u16,
     pub sample_rate: u32,
     pub sample_count: u32,
-    pub loop_start: u32,
-    pub loop_length: u32,
-    padding: u32,
-    audio_section_addr: u32,
-    audio_section_size: u32,
-    unk5: u32,
-    pub frame_count: u32,
-    pub frame_size: u16,
-    some_constant: u16,
-    pub orig_sample_rate: u32,
-    pub skip: u16,
-    pub stream_count: u8,
-    pub coupled_count: u8,
-    #[br(count = channel_count, align_after(0x10), pad_after(0x10))]
-    #[binwrite(align_after(0x10), pad_after(0x10))]
-    pub channel_mapping: Vec<u8>,
-    #[br(big, count = frame_count)]
-    #[binwrite(big)]
-    pub audio: Vec<LopusPacket>
+    /// Loop start and length, in samples
+    pub loop_points: Option<(u32, u32)>,
+}
+
+impl StreamInfo {
+    /// Length of the stream, in seconds
+    pub fn duration(&self) -> f64 {
+        self.sample_count as f64 / self.sample_rate.max(1) as f64
+    }
+}
+
+/// The stream held by an entry, which depends on the platform
+#[derive(Debug, Clone)]
+pub enum Payload {
+    Ktss(Ktss),
+    /// Obfuscated Ogg Vorbis, used on PC
+    Kovs(Kovs),
+    /// ATRAC9 in a RIFF file, used on PlayStation. Only passed through, never decoded.
+    Riff(Riff),
+}
+
+impl Payload {
+    /// Open a KTSS, or an Ogg/Opus/WAV file to convert, going by the extension. Ogg Vorbis files are stored as KOVS, AT9 files as they are.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> crate::error::Result<Self> {
+        let extension = path.as_ref().extension().and_then(|ext| ext.to_str()).map(str::to_ascii_lowercase);
+
+        if let Some("at9") = extension.as_deref() {
+            let mut at9 = vec![];
+            Error::open(&path)?.read_to_end(&mut at9)?;
+            return Ok(Payload::Riff(Riff::from_bytes(&at9)?));
+        }
+
+        if let Some("ogg") | Some("opus") = extension.as_deref() {
+            let mut ogg = vec![];
+            Error::open(&path)?.read_to_end(&mut ogg)?;
+
+            if super::is_vorbis(&ogg) {
+                return Ok(Payload::Kovs(Kovs::from_ogg(&ogg)?));
+            }
+        }
+
+        Ok(Payload::Ktss(Ktss::from_file(path)?))
+    }
+
+    /// Size of the payload once written
+    pub fn size(&self) -> u32 {
+        match self {
+            Payload::Ktss(ktss) => ktss.size(),
+            Payload::Kovs(kovs) => kovs.size(),
+            Payload::Riff(riff) => riff.size(),
+        }
+    }
+
+    pub fn codec_name(&self) -> String {
+        match self {
+            Payload::Ktss(ktss) => format!("{:?}", ktss.codec),
+            Payload::Kovs(_) => "Vorbis (KOVS)".to_string(),
+            Payload::Riff(riff) if riff.is_atrac9() => "ATRAC9 (RIFF)".to_string(),
+            Payload::Riff(riff) => format!("RIFF (format {:#x})", riff.format().unwrap_or(0)),
+        }
+    }
+
+    pub fn info(&self) -> crate::error::Result<StreamInfo> {
+        match self {
+            Payload::Ktss(ktss) => Ok(StreamInfo {
+                channel_count: ktss.channel_count,
+                sample_rate: ktss.sample_rate,
+                sample_count: ktss.sample_count,
+                loop_points: if ktss.loop_length > 0 && ktss.loop_start >= 0 { Some((ktss.loop_start as u32, ktss.loop_length)) } else { None },
+            }),
+            Payload::Kovs(kovs) => {
+                let vorbis = kovs.info()?;
+
+                Ok(StreamInfo {
+                    channel_count: vorbis.channel_count,
+                    sample_rate: vorbis.sample_rate,
+                    sample_count: vorbis.sample_count,
+                    // KOVS loops always run to the end of the stream
+                    loop_points: if kovs.loop_start != 0 { Some((kovs.loop_start, vorbis.sample_count.saturating_sub(kovs.loop_start))) } else { None },
+                })
+            },
+            Payload::Riff(riff) => riff.info(),
+        }
+    }
+
+    /// Change the loop points, or remove them with None. KOVS only keep the loop start, RIFF get a new smpl chunk.
+    pub fn set_loop(&mut self, loop_points: Option<(u32, u32)>) -> crate::error::Result<()> {
+        match self {
+            Payload::Ktss(ktss) => {
+                let (loop_start, loop_length) = loop_points.map(|(start, length)| (start as i32, length)).unwrap_or((0, 0));
+                ktss.loop_start = loop_start;
+                ktss.loop_length = loop_length;
+            },
+            Payload::Kovs(kovs) => kovs.loop_start = loop_points.map(|(start, _)| start).unwrap_or(0),
+            Payload::Riff(riff) => riff.set_loop(loop_points)?,
+        }
+
+        Ok(())
+    }
+}
+
+impl Payload {
+    /// Check the header of a KTSS against its audio. The other payloads have no header of their own to get wrong.
+    pub fn validate(&self) -> crate::error::Result<Vec<KtssIssue>> {
+        match self {
+            Payload::Ktss(ktss) => ktss.validate(),
+            Payload::Kovs(_) | Payload::Riff(_) => Ok(vec![]),
+        }
+    }
+
+    /// Derive the offsets and sizes in the header of a KTSS from its content, for payloads that were edited or built outside of an archive.
+    /// The other payloads keep their sizes up to date themselves.
+    pub fn recompute_layout(&mut self) {
+        if let Payload::Ktss(ktss) = self {
+            ktss.recompute_layout();
+        }
+    }
+
+    /// Fix the header of a KTSS from its audio, and return what was fixed
+    pub fn recompute(&mut self) -> crate::error::Result<Vec<KtssIssue>> {
+        match self {
+            Payload::Ktss(ktss) => ktss.recompute(),
+            Payload::Kovs(_) | Payload::Riff(_) => Ok(vec![]),
+        }
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Payload::Ktss(Ktss::default())
+    }
+}
+
+impl From<Ktss> for Payload {
+    fn from(ktss: Ktss) -> Self {
+        Payload::Ktss(ktss)
+    }
 }
 
-impl Ktss {
-    pub fn open<P: AsRef<Path>>(path: P) -> BinResult<Self> {
-        Self::read(&mut BufReader::new(File::open(path)?))
+impl From<Kovs> for Payload {
+    fn from(kovs: Kovs) -> Self {
+        Payload::Kovs(kovs)
     }
 }
 
-#[derive(BinRead, BinWrite, Debug, Default, Clone)]
-pub struct LopusPacket {
-    pub size: u32,
-    pub unk: u32,
-    #[br(count = size)]
-    pub content: Vec<u8>,
-}
\ No newline at end of file
+impl From<Riff> for Payload {
+    fn from(riff: Riff) -> Self {
+        Payload::Riff(riff)
+    }
+}
+
+impl BinRead for Payload {
+    type Args = ();
+
+    fn read_options<R: binread::io::Read + Seek>(reader: &mut R, options: &ReadOptions, _args: Self::Args) -> BinResult<Self> {
+        let pos = reader.seek(SeekFrom::Current(0))?;
+        let magic = u32::read_options(reader, options, ())?;
+        reader.seek(SeekFrom::Start(pos))?;
+
+        Ok(match magic {
+            KOVS_MAGIC => Payload::Kovs(Kovs::read_options(reader, options, ())?),
+            RIFF_MAGIC => Payload::Riff(Riff::read_options(reader, options, ())?),
+            _ => Payload::Ktss(Ktss::read_options(reader, options, ())?),
+        })
+    }
+}
+
+impl BinWrite for Payload {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> Result<()> {
+        match self {
+            Payload::Ktss(ktss) => ktss.write_options(writer, options),
+            Payload::Kovs(kovs) => kovs.write_options(writer, options),
+            Payload::Riff(riff) => riff.write_options(writer, options),
+        }
+    }
+}
+
+impl BinWrite for MusicSection {
+    fn write_options<W: Write>(&self, writer: &mut W, options: &WriterOption) -> Result<()> {
+        (self.section_size, self.link_id, self.header_size, self.ktss_size).write_options(writer, options)?;
+        // The section magic is written by the container, so it has to be accounted for by hand here
+        vec![0u8; self.header_size.saturating_sub(0x14) as usize].write_options(writer, options)?;
+
+        let mut payload = vec![];
+        self.payload.write_options(&mut payload, options)?;
+        payload.write_options(writer, options)?;
+
+        let written = self.header_size as usize + payload.len();
+        vec![0u8; (0x40 - (written % 0x40)) % 0x40].write_options(writer, options)
+    }
+}
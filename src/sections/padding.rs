@@ -9,6 +9,7 @@ use binwrite::{
 #[derive(BinRead, BinWrite, Debug, Default, Clone)]
 #[br(little)]
 pub struct PaddingSection {
+    #[br(assert(section_size >= 0x8))]
     pub section_size: u32,
     #[br(count = section_size - 0x8)]
     padding: Vec<u8>,
@@ -0,0 +1,20 @@
+use binread::{
+    BinRead,
+};
+
+use binwrite::{
+    BinWrite,
+};
+
+/// Any section we don't know the layout of. Kept as is so it can be written back untouched.
+/// Known magics are never taken as raw, so a section that fails to parse reports an error instead of being skipped.
+#[derive(BinRead, BinWrite, Debug, Default, Clone)]
+#[br(little)]
+pub struct RawSection {
+    #[br(assert(!crate::Section::is_known_magic(magic)))]
+    pub magic: u32,
+    #[br(assert(section_size >= 0x8))]
+    pub section_size: u32,
+    #[br(count = section_size - 0x8)]
+    pub data: Vec<u8>,
+}
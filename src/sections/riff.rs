@@ -0,0 +1,216 @@
+use binread::BinRead;
+use binwrite::BinWrite;
+
+use super::StreamInfo;
+use crate::error::{Error, Result};
+use crate::wav::{riff_chunks, smpl_chunk, smpl_loop};
+
+/// "RIFF"
+pub const RIFF_MAGIC: u32 = 0x46464952;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// KSDATAFORMAT_SUBTYPE_ATRAC9, {47E142D2-36BA-4D8D-88FC-61654F8C836C}, as stored in the fmt chunk
+const ATRAC9_SUBFORMAT: [u8; 16] = [0xD2, 0x42, 0xE1, 0x47, 0xBA, 0x36, 0x8D, 0x4D, 0x88, 0xFC, 0x61, 0x65, 0x4F, 0x8C, 0x83, 0x6C];
+
+/// RIFF WAVE file, usually holding ATRAC9 on PlayStation. It is kept as is, only the header chunks are looked at.
+#[derive(BinRead, BinWrite, Debug, Default, Clone)]
+pub struct Riff {
+    pub magic: u32,
+    pub riff_size: u32,
+    /// Everything after the RIFF header, starting with the "WAVE" form type
+    #[br(count = riff_size)]
+    data: Vec<u8>,
+}
+
+impl Riff {
+    /// Wrap a complete RIFF file, making sure its chunks can be read
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        riff_chunks(bytes)?;
+
+        Ok(Riff {
+            magic: RIFF_MAGIC,
+            riff_size: bytes.len() as u32 - 8,
+            data: bytes[8..].to_vec(),
+        })
+    }
+
+    /// The complete RIFF file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size() as usize);
+        bytes.extend(b"RIFF");
+        bytes.extend(&(self.data.len() as u32).to_le_bytes());
+        bytes.extend(&self.data);
+        bytes
+    }
+
+    pub fn size(&self) -> u32 {
+        8 + self.data.len() as u32
+    }
+
+    /// Format tag of the fmt chunk. 0xFFFE (extensible) is what ATRAC9 uses.
+    pub fn format(&self) -> Result<u16> {
+        Ok(self.fmt()?.0)
+    }
+
+    fn fmt(&self) -> Result<(u16, u16, u32, u16)> {
+        let chunk = self.fmt_chunk()?;
+
+        Ok((
+            u16::from_le_bytes([chunk[0], chunk[1]]),
+            u16::from_le_bytes([chunk[2], chunk[3]]),
+            u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+            u16::from_le_bytes([chunk[12], chunk[13]]),
+        ))
+    }
+
+    fn fmt_chunk(&self) -> Result<Vec<u8>> {
+        let bytes = self.to_bytes();
+
+        match riff_chunks(&bytes)?.into_iter().find(|(id, _)| id == b"fmt ") {
+            Some((_, chunk)) if chunk.len() >= 0x10 => Ok(chunk.to_vec()),
+            _ => Err(Error::UnsupportedWav),
+        }
+    }
+
+    /// Read the stream properties from the fmt, fact and smpl chunks
+    pub fn info(&self) -> Result<StreamInfo> {
+        let (format, channel_count, sample_rate, block_align) = self.fmt()?;
+        let bytes = self.to_bytes();
+        let chunks = riff_chunks(&bytes)?;
+
+        let find = |name: &[u8; 4]| chunks.iter().find(|(id, _)| id == name).map(|(_, chunk)| *chunk);
+
+        let read_u32 = |chunk: &[u8], pos: usize| u32::from_le_bytes([chunk[pos], chunk[pos + 1], chunk[pos + 2], chunk[pos + 3]]);
+
+        // Compressed formats store the sample count in the fact chunk, PCM can be counted instead.
+        // ATRAC9 adds the encoder delay at 0x8, which is decoded but never played.
+        let sample_count = match (find(b"fact"), find(b"data")) {
+            (Some(fact), _) if fact.len() >= 12 && self.is_atrac9() => read_u32(fact, 0).saturating_sub(read_u32(fact, 8)),
+            (Some(fact), _) if fact.len() >= 4 => read_u32(fact, 0),
+            (_, Some(data)) if format == WAVE_FORMAT_PCM && block_align > 0 => data.len() as u32 / block_align as u32,
+            _ => 0,
+        };
+
+        Ok(StreamInfo {
+            channel_count: channel_count as u8,
+            sample_rate,
+            sample_count,
+            loop_points: find(b"smpl").and_then(smpl_loop),
+        })
+    }
+
+    /// Replace the smpl chunk with one holding the loop points provided, or remove it with None
+    pub fn set_loop(&mut self, loop_points: Option<(u32, u32)>) -> Result<()> {
+        let (_, _, sample_rate, _) = self.fmt()?;
+        let bytes = self.to_bytes();
+
+        let mut data = b"WAVE".to_vec();
+
+        for (id, chunk) in riff_chunks(&bytes)? {
+            if &id == b"smpl" {
+                continue;
+            }
+
+            // The smpl chunk goes right before the samples, like audio editors do
+            if &id == b"data" {
+                if let Some((start, length)) = loop_points {
+                    data.extend(smpl_chunk(sample_rate, start, length));
+                }
+            }
+
+            data.extend(&id);
+            data.extend(&(chunk.len() as u32).to_le_bytes());
+            data.extend(chunk);
+            if chunk.len() % 2 != 0 {
+                data.push(0);
+            }
+        }
+
+        self.riff_size = data.len() as u32;
+        self.data = data;
+
+        Ok(())
+    }
+
+    /// Whether this is ATRAC9, which is the only RIFF format the games are known to use.
+    /// ATRAC9 is an extensible format, told apart from the others by the subformat GUID at 0x18 of the fmt chunk.
+    pub fn is_atrac9(&self) -> bool {
+        match self.fmt_chunk() {
+            Ok(chunk) if chunk.len() >= 0x28 => {
+                u16::from_le_bytes([chunk[0], chunk[1]]) == WAVE_FORMAT_EXTENSIBLE && chunk[0x18..0x28] == ATRAC9_SUBFORMAT
+            },
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at9(subformat: [u8; 16], delay: u32) -> Vec<u8> {
+        let mut fmt = vec![];
+        fmt.extend(&WAVE_FORMAT_EXTENSIBLE.to_le_bytes());
+        fmt.extend(&2u16.to_le_bytes());
+        fmt.extend(&48000u32.to_le_bytes());
+        fmt.extend(&0u32.to_le_bytes());
+        fmt.extend(&0x100u16.to_le_bytes());
+        fmt.extend(&0u16.to_le_bytes());
+        fmt.extend(&[0; 8]);
+        fmt.extend(&subformat);
+        fmt.extend(&[0; 0xC]);
+
+        let mut fact = vec![];
+        fact.extend(&(48000 + delay).to_le_bytes());
+        fact.extend(&0u32.to_le_bytes());
+        fact.extend(&delay.to_le_bytes());
+
+        let mut data = b"WAVE".to_vec();
+        for (id, chunk) in [(b"fmt ", fmt), (b"fact", fact), (b"data", vec![0; 0x301])] {
+            data.extend(id);
+            data.extend(&(chunk.len() as u32).to_le_bytes());
+            data.extend(&chunk);
+            if chunk.len() % 2 != 0 {
+                data.push(0);
+            }
+        }
+
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend(&(data.len() as u32).to_le_bytes());
+        bytes.extend(data);
+        bytes
+    }
+
+    #[test]
+    fn test_riff_info_and_loop() {
+        let bytes = at9(ATRAC9_SUBFORMAT, 0);
+        let mut riff = Riff::from_bytes(&bytes).unwrap();
+
+        assert!(riff.is_atrac9());
+        assert_eq!(riff.to_bytes(), bytes);
+        assert_eq!(riff.info().unwrap(), StreamInfo { channel_count: 2, sample_rate: 48000, sample_count: 48000, loop_points: None });
+
+        riff.set_loop(Some((1000, 20000))).unwrap();
+        assert_eq!(riff.info().unwrap().loop_points, Some((1000, 20000)));
+        assert_eq!(riff.size(), bytes.len() as u32 + 8 + 0x3C);
+
+        riff.set_loop(None).unwrap();
+        assert_eq!(riff.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_atrac9_detection_and_delay() {
+        let riff = Riff::from_bytes(&at9(ATRAC9_SUBFORMAT, 256)).unwrap();
+        assert!(riff.is_atrac9());
+        assert_eq!(riff.info().unwrap().sample_count, 48000);
+
+        // Same extensible header with a PCM subformat, whose fact chunk has no delay
+        let mut pcm = ATRAC9_SUBFORMAT;
+        pcm[..4].copy_from_slice(&1u32.to_le_bytes());
+        let riff = Riff::from_bytes(&at9(pcm, 256)).unwrap();
+        assert!(!riff.is_atrac9());
+        assert_eq!(riff.info().unwrap().sample_count, 48256);
+    }
+}
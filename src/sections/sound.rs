@@ -1,4 +1,5 @@
 use binread::{
+    io::Cursor,
     BinRead,
 };
 
@@ -6,16 +7,74 @@ use binwrite::{
     BinWrite,
 };
 
-// TODO: Rework this to use a subsection
-#[derive(BinRead, BinWrite, Debug, Default, Clone)]
+use super::{read_alignment, DspBody, DspChannel, Ktss, KtssBody, KtssCodec, Payload, DSP_FRAME_SIZE, DSP_SAMPLES_PER_FRAME};
+use crate::error::Error;
+
+#[derive(BinRead, BinWrite, Debug, Clone)]
 #[br(little)]
-pub struct SoundSection {
+pub struct KtssCompanionSectionHeader {
     pub section_size: u32,
     pub link_id: u32,
-    #[br(count = 0x10)]
-    unknown_1: Vec<u8>,
-    pub header_size: u32,
+    unk1: u16,
+    unk2: u16,
+    pub stream_count: u32,
+    subheader1_addr: u32,
+    #[br(assert(subheader1_addr >= subheader2_addr))]
+    subheader2_addr: u32,
+    #[br(count = subheader1_addr - subheader2_addr)]
+    name: Vec<u8>,
+    #[br(assert(second_sect_addr >= subheader1_addr + 4))]
+    second_sect_addr: u32,
+    #[br(count = second_sect_addr - subheader1_addr - 4)]
+    padding: Vec<u8>,
+    #[br(parse_with = read_alignment, args(0x8))]
+    alignment: Vec<u8>,
+}
+
+impl KtssCompanionSectionHeader {
+    /// Header of a new section, to be written at `offset` in the archive. The section size is left to the caller.
+    /// The name goes right after the fixed fields, with nothing between it and second_sect_addr.
+    pub fn new(link_id: u32, name: &[u8], offset: u32) -> Self {
+        let subheader2_addr = 0x20;
+        let subheader1_addr = subheader2_addr + name.len() as u32;
+        let end = offset + 0x20 + name.len() as u32;
+
+        KtssCompanionSectionHeader {
+            section_size: 0,
+            link_id,
+            unk1: 0,
+            unk2: 0,
+            stream_count: 1,
+            subheader1_addr,
+            subheader2_addr,
+            name: name.to_vec(),
+            second_sect_addr: subheader1_addr + 4,
+            padding: vec![],
+            alignment: vec![0; ((8 - end % 8) % 8) as usize],
+        }
+    }
+
+    /// Size of the header, counting the section magic
+    pub fn size(&self) -> u32 {
+        0x20 + (self.name.len() + self.padding.len() + self.alignment.len()) as u32
+    }
+}
+
+/// Size of the KTSS companion subsection up to the trailing padding
+const COMPANION_SUBSECTION_SIZE: u32 = 0x40;
+/// Magic of the subsection describing an entry of the Ktsl2stbin. Any other one holds its own sound.
+pub const COMPANION_SUBSECTION_MAGIC: u32 = 0x7D43D038;
+/// Subsection magic given to the embedded sounds we write. Taken from vgmstream's list of internal streams, not from a game.
+pub const EMBEDDED_SOUND_SUBSECTION_MAGIC: u32 = 0x2EB6CA6F;
+
+// Describes a KTSS/KOVS/RIFF entry of the Ktsl2stbin. Sections holding their own sound are read as EmbeddedSoundSection instead.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[br(little)]
+pub struct KtssCompanionSection {
+    #[br(assert(header.section_size >= header.size() + COMPANION_SUBSECTION_SIZE))]
+    pub header: KtssCompanionSectionHeader,
     // This one actually is important and determines what follows, magic for the 0x60 "KTSS companion" subsection is 0x7D43D038
+    #[br(assert(subsection_magic == COMPANION_SUBSECTION_MAGIC))]
     subsection_magic: u32,
     section_size_2: u32,
     unknown_2: u32,
@@ -25,12 +84,208 @@ pub struct SoundSection {
     pub sample_rate: u32,
     pub sample_count: u32,
     unknown_4: u32,
-    pub loop_start: u32,
+    pub loop_start: i32,
     #[br(count = 0xC)]
     unknown_5: Vec<u8>,
     pub ktss_offset: u32,
     pub ktss_size: u32,
     unknown_6: u32,
-    #[br(count = section_size - 0x60)]
-    unk: Vec<u8>,
-}
\ No newline at end of file
+    // Whatever is left until the end of the section
+    #[br(count = header.section_size - header.size() - COMPANION_SUBSECTION_SIZE)]
+    padding: Vec<u8>,
+}
+
+impl KtssCompanionSection {
+    /// Copy the informations the game needs from the entry this section describes. The offset has to be set separately.
+    pub fn sync_with(&mut self, payload: &Payload) -> crate::error::Result<()> {
+        let info = payload.info()?;
+
+        self.ktss_size = payload.size();
+        self.channel_count = info.channel_count as u32;
+        self.loop_start = info.loop_points.map(|(start, _)| start as i32).unwrap_or(-1);
+        self.sample_count = info.sample_count;
+        self.sample_rate = info.sample_rate;
+
+        Ok(())
+    }
+}
+
+// A sound stored in the Ktsl2asbin itself, usually a short effect or voice line. The subsection has the same shape as the companion one,
+// with the codec where the companion has transition_related, and the location of the sound where it has the KTSS offset and size.
+// Only DSP ADPCM is decoded: one standard DSP header per channel, then the frames of each channel one after the other.
+// None of this was checked against retail files, so whatever follows the subsection is kept as is.
+#[derive(BinRead, BinWrite, Debug, Clone)]
+#[br(little)]
+pub struct EmbeddedSoundSection {
+    #[br(assert(header.section_size >= header.size() + COMPANION_SUBSECTION_SIZE))]
+    pub header: KtssCompanionSectionHeader,
+    #[br(assert(subsection_magic != COMPANION_SUBSECTION_MAGIC))]
+    pub subsection_magic: u32,
+    section_size_2: u32,
+    unknown_2: u32,
+    pub channel_count: u32,
+    /// Same ids as the KTSS codecs
+    pub codec: u32,
+    unknown_3: u32,
+    pub sample_rate: u32,
+    pub sample_count: u32,
+    unknown_4: u32,
+    pub loop_start: i32,
+    #[br(count = 0xC)]
+    unknown_5: Vec<u8>,
+    /// Where the sound starts, from the start of the section
+    pub data_offset: u32,
+    pub data_size: u32,
+    unknown_6: u32,
+    /// Everything after the subsection, the sound included
+    #[br(count = header.section_size - header.size() - COMPANION_SUBSECTION_SIZE)]
+    body: Vec<u8>,
+}
+
+impl EmbeddedSoundSection {
+    /// Embed a DSP KTSS, in a section to be written at `offset` in the Ktsl2asbin.
+    /// Only the loop start is stored in the section, the DSP headers keep the loop end as well.
+    pub fn from_ktss(link_id: u32, name: &[u8], ktss: &Ktss, offset: u32) -> crate::error::Result<Self> {
+        let dsp = match &ktss.body {
+            KtssBody::Dsp(dsp) => dsp,
+            _ => return Err(Error::UnsupportedCodec { codec: ktss.codec.into() }),
+        };
+
+        let loop_points = if ktss.loop_length > 0 && ktss.loop_start >= 0 { Some((ktss.loop_start as u32, ktss.loop_length)) } else { None };
+        let frames: Vec<Vec<u8>> = (0..dsp.channels.len()).map(|channel| dsp.channel_frames(channel).flatten().copied().collect()).collect();
+
+        let mut sound = vec![];
+        for (channel, frames) in dsp.channels.iter().zip(&frames) {
+            let header = DspHeader::new(channel.clone(), ktss.sample_rate, ktss.sample_count, frames.len() / DSP_FRAME_SIZE, loop_points);
+            header.write(&mut sound)?;
+        }
+        for frames in frames {
+            sound.extend(frames);
+        }
+
+        let mut header = KtssCompanionSectionHeader::new(link_id, name, offset);
+        let data_offset = header.size() + COMPANION_SUBSECTION_SIZE;
+        header.section_size = data_offset + sound.len() as u32;
+
+        Ok(EmbeddedSoundSection {
+            header,
+            subsection_magic: EMBEDDED_SOUND_SUBSECTION_MAGIC,
+            section_size_2: COMPANION_SUBSECTION_SIZE,
+            unknown_2: 0,
+            channel_count: dsp.channels.len() as u32,
+            codec: u8::from(KtssCodec::Dsp) as u32,
+            unknown_3: 0,
+            sample_rate: ktss.sample_rate,
+            sample_count: ktss.sample_count,
+            unknown_4: 0,
+            loop_start: loop_points.map(|(start, _)| start as i32).unwrap_or(-1),
+            unknown_5: vec![0; 0xC],
+            data_offset,
+            data_size: sound.len() as u32,
+            unknown_6: 0,
+            body: sound,
+        })
+    }
+
+    pub fn codec(&self) -> KtssCodec {
+        KtssCodec::from(self.codec as u8)
+    }
+
+    /// The bytes of the sound, as pointed to by the subsection
+    pub fn sound(&self) -> crate::error::Result<&[u8]> {
+        let body_start = self.header.size() + COMPANION_SUBSECTION_SIZE;
+        let start = self.data_offset.checked_sub(body_start).map(|start| start as usize);
+
+        match start.and_then(|start| self.body.get(start..start + self.data_size as usize)) {
+            Some(sound) => Ok(sound),
+            None => Err(Error::SizeMismatch { what: "embedded sound", expected: self.data_offset as u64 + self.data_size as u64, found: self.header.section_size as u64 }),
+        }
+    }
+
+    /// Rebuild a standalone KTSS out of the sound, so it can be decoded like any entry
+    pub fn to_ktss(&self) -> crate::error::Result<Ktss> {
+        if self.codec() != KtssCodec::Dsp {
+            return Err(Error::UnsupportedCodec { codec: self.codec as u8 });
+        }
+
+        let mut reader = Cursor::new(self.sound()?);
+        let headers = (0..self.channel_count).map(|_| DspHeader::read(&mut reader)).collect::<binread::BinResult<Vec<_>>>()?;
+
+        let mut frames = Vec::with_capacity(headers.len());
+        for header in &headers {
+            let mut channel = vec![0u8; header.frame_count() * DSP_FRAME_SIZE];
+            std::io::Read::read_exact(&mut reader, &mut channel)?;
+            frames.push(channel);
+        }
+
+        // KTSS interleave the channels frame by frame
+        let frame_count = headers.iter().map(DspHeader::frame_count).max().unwrap_or(0);
+        let mut data = Vec::with_capacity(frame_count * frames.len() * DSP_FRAME_SIZE);
+        for i in 0..frame_count {
+            for channel in &frames {
+                data.extend(channel.get(i * DSP_FRAME_SIZE..(i + 1) * DSP_FRAME_SIZE).unwrap_or(&[0; DSP_FRAME_SIZE]));
+            }
+        }
+
+        let mut ktss = Ktss::new(KtssBody::Dsp(DspBody::new(headers.into_iter().map(|header| header.channel).collect(), data)));
+        ktss.sample_rate = self.sample_rate;
+        ktss.sample_count = self.sample_count;
+
+        // Like companion sections, only the loop start is stored and the loop runs to the end
+        if self.loop_start >= 0 && (self.loop_start as u32) < self.sample_count {
+            ktss.loop_start = self.loop_start;
+            ktss.loop_length = self.sample_count - self.loop_start as u32;
+        }
+
+        ktss.recompute_layout();
+        Ok(ktss)
+    }
+}
+
+/// Standard Nintendo DSP header, as found before the frames of each channel of an embedded sound
+#[derive(BinRead, BinWrite, Debug, Default, Clone, PartialEq)]
+#[br(little)]
+pub struct DspHeader {
+    pub sample_count: u32,
+    pub nibble_count: u32,
+    pub sample_rate: u32,
+    pub loop_flag: u16,
+    pub format: u16,
+    pub loop_start_address: u32,
+    pub loop_end_address: u32,
+    pub current_address: u32,
+    pub channel: DspChannel,
+    #[br(count = 0x16)]
+    padding: Vec<u8>,
+}
+
+impl DspHeader {
+    /// Header of a channel of `frame_count` frames. The loop points are in samples.
+    pub fn new(channel: DspChannel, sample_rate: u32, sample_count: u32, frame_count: usize, loop_points: Option<(u32, u32)>) -> Self {
+        let (loop_start, loop_end) = loop_points.map(|(start, length)| (start, start + length - 1)).unwrap_or((0, sample_count.saturating_sub(1)));
+
+        DspHeader {
+            sample_count,
+            nibble_count: (frame_count * 2 * DSP_FRAME_SIZE) as u32,
+            sample_rate,
+            loop_flag: loop_points.is_some() as u16,
+            format: 0,
+            loop_start_address: nibble_address(loop_start),
+            loop_end_address: nibble_address(loop_end),
+            current_address: 2,
+            channel,
+            padding: vec![0; 0x16],
+        }
+    }
+
+    /// Number of frames holding the samples, going by the nibble count which includes the frame headers
+    pub fn frame_count(&self) -> usize {
+        (self.nibble_count as usize).div_ceil(2 * DSP_FRAME_SIZE)
+    }
+}
+
+/// Address of a sample in nibbles, skipping the header byte of every frame
+fn nibble_address(sample: u32) -> u32 {
+    let samples_per_frame = DSP_SAMPLES_PER_FRAME as u32;
+    sample / samples_per_frame * 2 * DSP_FRAME_SIZE as u32 + 2 + sample % samples_per_frame
+}
@@ -9,8 +9,9 @@ use binwrite::{
 #[derive(BinRead, BinWrite, Debug, Default, Clone)]
 #[br(little)]
 pub struct UnknownSection {
+    #[br(assert(section_size >= 0xC))]
     pub section_size: u32,
-    link_id: u32,
-    #[br(count = section_size - 0x8)]
+    pub link_id: u32,
+    #[br(count = section_size - 0xC)]
     unknown_1: Vec<u8>,
-}
\ No newline at end of file
+}
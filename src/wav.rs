@@ -0,0 +1,170 @@
+//! Minimal PCM16 WAV support, with loop points stored in a smpl chunk like most audio editors do.
+
+use std::io::{Read, Write};
+
+use crate::error::{Error, Result};
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Wav {
+    pub channel_count: u16,
+    pub sample_rate: u32,
+    /// Samples of every channel, interleaved
+    pub samples: Vec<i16>,
+    /// Loop start and length, in samples per channel
+    pub loop_points: Option<(u32, u32)>,
+}
+
+impl Wav {
+    pub fn frame_count(&self) -> usize {
+        self.samples.len() / self.channel_count.max(1) as usize
+    }
+
+    /// Read a PCM16 WAV, along with the first loop of its smpl chunk if it has one
+    pub fn read<R: Read>(mut reader: R) -> Result<Self> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+
+        let read_u16 = |chunk: &[u8], pos: usize| u16::from_le_bytes([chunk[pos], chunk[pos + 1]]);
+
+        let mut wav = Wav::default();
+        let mut format = None;
+
+        for (id, chunk) in riff_chunks(&data)? {
+            match &id {
+                b"fmt " if chunk.len() >= 0x10 => {
+                    // 1 is PCM, 0xFFFE is WAVE_FORMAT_EXTENSIBLE which is fine as long as the samples are 16 bits
+                    format = Some((read_u16(chunk, 0), read_u16(chunk, 14)));
+                    wav.channel_count = read_u16(chunk, 2);
+                    wav.sample_rate = read_u32(chunk, 4);
+                },
+                b"smpl" => wav.loop_points = smpl_loop(chunk),
+                b"data" => {
+                    wav.samples = chunk.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])).collect();
+                },
+                _ => (),
+            }
+        }
+
+        match format {
+            Some((1, 16)) | Some((0xFFFE, 16)) if wav.channel_count > 0 => Ok(wav),
+            _ => Err(Error::UnsupportedWav),
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        let data_size = self.samples.len() as u32 * 2;
+        let smpl_size = if self.loop_points.is_some() { 8 + 0x3C } else { 0 };
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&(4 + (8 + 0x10) + smpl_size + 8 + data_size).to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        let block_align = self.channel_count * 2;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&0x10u32.to_le_bytes())?;
+        // PCM
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&self.channel_count.to_le_bytes())?;
+        writer.write_all(&self.sample_rate.to_le_bytes())?;
+        writer.write_all(&(self.sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&16u16.to_le_bytes())?;
+
+        if let Some((start, length)) = self.loop_points {
+            writer.write_all(&smpl_chunk(self.sample_rate, start, length))?;
+        }
+
+        writer.write_all(b"data")?;
+        writer.write_all(&data_size.to_le_bytes())?;
+
+        let mut data = Vec::with_capacity(data_size as usize);
+        for sample in &self.samples {
+            data.extend(&sample.to_le_bytes());
+        }
+        writer.write_all(&data)?;
+
+        Ok(())
+    }
+}
+
+fn read_u32(chunk: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([chunk[pos], chunk[pos + 1], chunk[pos + 2], chunk[pos + 3]])
+}
+
+/// Split a RIFF WAVE file in chunks, as (ID, content) pairs
+pub fn riff_chunks(data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(Error::BadMagic { pos: 0 });
+    }
+
+    let mut chunks = vec![];
+    let mut pos = 12;
+
+    while pos + 8 <= data.len() {
+        let mut id = [0; 4];
+        id.copy_from_slice(&data[pos..pos + 4]);
+        let size = read_u32(data, pos + 4) as usize;
+        let body = pos + 8;
+
+        if body + size > data.len() {
+            return Err(Error::SizeMismatch { what: "WAV chunk", expected: size as u64, found: (data.len() - body) as u64 });
+        }
+
+        chunks.push((id, &data[body..body + size]));
+
+        // Chunks are padded to an even size
+        pos = body + size + size % 2;
+    }
+
+    Ok(chunks)
+}
+
+/// Start and length of the first loop of a smpl chunk
+pub fn smpl_loop(chunk: &[u8]) -> Option<(u32, u32)> {
+    if chunk.len() < 0x24 + 0x18 || read_u32(chunk, 0x1C) == 0 {
+        return None;
+    }
+
+    let start = read_u32(chunk, 0x24 + 8);
+    let end = read_u32(chunk, 0x24 + 12);
+    Some((start, end.saturating_sub(start) + 1))
+}
+
+/// A complete smpl chunk with a single forward loop
+pub fn smpl_chunk(sample_rate: u32, start: u32, length: u32) -> Vec<u8> {
+    let mut chunk = vec![];
+    chunk.extend(b"smpl");
+    chunk.extend(&0x3Cu32.to_le_bytes());
+
+    // Manufacturer, product, sample period, MIDI unity note, pitch fraction, SMPTE format and offset
+    let period = 1_000_000_000 / sample_rate.max(1);
+    // A single loop, and no sampler data
+    let header = [0, 0, period, 60, 0, 0, 0, 1, 0];
+    // Cue point ID, forward loop, start, inclusive end, fraction and infinite play count
+    let first_loop = [0, 0, start, start + length.max(1) - 1, 0, 0];
+
+    for value in header.iter().chain(first_loop.iter()) {
+        chunk.extend(&u32::to_le_bytes(*value));
+    }
+
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let wav = Wav {
+            channel_count: 2,
+            sample_rate: 32000,
+            samples: (0..100).map(|i| i * 300 - 15000).collect(),
+            loop_points: Some((10, 30)),
+        };
+
+        let mut bytes = vec![];
+        wav.write(&mut bytes).unwrap();
+        assert_eq!(Wav::read(bytes.as_slice()).unwrap(), wav);
+    }
+}
@@ -0,0 +1,570 @@
+//! Round-trip tests on synthetic archives: every section type is read then written back, and has to come out byte for byte.
+
+use binread::{io::Cursor, BinRead};
+use binwrite::BinWrite;
+
+use ktsl_tool::{DspBody, DspChannel, Error, ExportFormat, Kovs, Ktsl, Ktss, KtssBody, KtssCodec, KtssIssue, LopusPacket, MusicSection, OpusBody, Payload, Riff, Section, DSP_FRAME_SIZE};
+use ktsl_tool::ogg::{OggWriter, FLAG_BOS, FLAG_EOS};
+use ktsl_tool::wav::Wav;
+
+/// Builds the bytes of an archive section by section, keeping track of the absolute offset for alignments
+struct ArchiveBuilder {
+    bytes: Vec<u8>,
+}
+
+impl ArchiveBuilder {
+    fn new(filetype: u32) -> Self {
+        let mut bytes = vec![];
+        bytes.extend(b"KTSR");
+        bytes.extend(&filetype.to_le_bytes());
+        bytes.extend(&1u16.to_le_bytes());
+        bytes.extend(&0x400u16.to_le_bytes());
+        bytes.extend(&0xB75674CEu32.to_le_bytes());
+        bytes.resize(0x40, 0);
+
+        ArchiveBuilder {
+            bytes,
+        }
+    }
+
+    fn offset(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn push(&mut self, section: Vec<u8>) -> &mut Self {
+        self.bytes.extend(section);
+        self
+    }
+
+    fn music(&mut self, link_id: u32) -> &mut Self {
+        let mut opus = OpusBody::default();
+        opus.channel_mapping = vec![0, 1];
+        opus.frame_count = 3;
+        opus.frame_size = 0x10;
+        opus.audio = (0..3u8).map(|i| LopusPacket { size: 5, unk: 0, content: vec![i; 5] }).collect();
+
+        let mut ktss = Ktss::new(KtssBody::Opus(opus));
+        ktss.channel_count = 2;
+        ktss.sample_rate = 48000;
+        ktss.sample_count = 2880;
+
+        let mut section = vec![];
+        Section::Music(MusicSection::from_ktss(link_id, ktss)).write(&mut section).unwrap();
+        self.push(section)
+    }
+
+    fn kovs(&mut self, link_id: u32) -> &mut Self {
+        let mut ident = b"\x01vorbis".to_vec();
+        ident.extend(&0u32.to_le_bytes());
+        ident.push(1);
+        ident.extend(&22050u32.to_le_bytes());
+        ident.extend(&[0; 13]);
+
+        let mut writer = OggWriter::new(vec![], link_id);
+        writer.write_page(&[&ident], 0, FLAG_BOS).unwrap();
+        writer.write_page(&[&[0x5A; 0x180]], 44100, FLAG_EOS).unwrap();
+
+        let mut kovs = Kovs::from_ogg(&writer.into_inner()).unwrap();
+        kovs.loop_start = 100;
+
+        let mut section = vec![];
+        Section::Music(MusicSection::from_payload(link_id, Payload::Kovs(kovs))).write(&mut section).unwrap();
+        self.push(section)
+    }
+
+    fn dsp(&mut self, link_id: u32) -> &mut Self {
+        let mut ktss = Ktss::new(KtssBody::Dsp(DspBody::new(vec![DspChannel::default()], vec![0x11; 0x40])));
+        ktss.channel_count = 1;
+        ktss.sample_rate = 32000;
+        ktss.sample_count = 8 * 14;
+
+        let mut section = vec![];
+        Section::Music(MusicSection::from_ktss(link_id, ktss)).write(&mut section).unwrap();
+        self.push(section)
+    }
+
+    fn riff(&mut self, link_id: u32) -> &mut Self {
+        let mut fmt = vec![];
+        fmt.extend(&0xFFFEu16.to_le_bytes());
+        fmt.extend(&2u16.to_le_bytes());
+        fmt.extend(&48000u32.to_le_bytes());
+        fmt.resize(0x18, 0);
+        // ATRAC9 subformat GUID
+        fmt.extend(&[0xD2, 0x42, 0xE1, 0x47, 0xBA, 0x36, 0x8D, 0x4D, 0x88, 0xFC, 0x61, 0x65, 0x4F, 0x8C, 0x83, 0x6C]);
+        fmt.resize(0x34, 0);
+
+        let mut data = b"WAVE".to_vec();
+        for (id, chunk) in [(b"fmt ", fmt), (b"fact", 9600u32.to_le_bytes().to_vec()), (b"data", vec![0x33; 0x200])] {
+            data.extend(id);
+            data.extend(&(chunk.len() as u32).to_le_bytes());
+            data.extend(chunk);
+        }
+
+        let mut at9 = b"RIFF".to_vec();
+        at9.extend(&(data.len() as u32).to_le_bytes());
+        at9.extend(data);
+
+        let mut section = vec![];
+        Section::Music(MusicSection::from_payload(link_id, Payload::Riff(Riff::from_bytes(&at9).unwrap()))).write(&mut section).unwrap();
+        self.push(section)
+    }
+
+    fn padding(&mut self, size: u32) -> &mut Self {
+        let mut section = vec![];
+        section.extend(&0xA8DB7261u32.to_le_bytes());
+        section.extend(&size.to_le_bytes());
+        section.resize(size as usize, 0);
+        self.push(section)
+    }
+
+    fn info(&mut self, subsection_magic: u32, size: u32) -> &mut Self {
+        let mut section = vec![];
+        section.extend(&0x368C88BDu32.to_le_bytes());
+        section.extend(&size.to_le_bytes());
+        section.extend(&0x1234u32.to_le_bytes());
+        section.extend(&[1, 0, 2, 0]);
+        section.resize(0x18, 0);
+        section.extend(&subsection_magic.to_le_bytes());
+        section.extend((0..size - 0x1C).map(|i| i as u8));
+        self.push(section)
+    }
+
+    fn companion(&mut self, link_id: u32) -> &mut Self {
+        let start = self.offset();
+        let mut section = vec![];
+        section.extend(&0x70CBCCC5u32.to_le_bytes());
+        // Section size, patched once everything is in
+        section.extend(&0u32.to_le_bytes());
+        section.extend(&link_id.to_le_bytes());
+        section.extend(&[3, 0, 4, 0]);
+        section.extend(&1u32.to_le_bytes());
+        // subheader1_addr and subheader2_addr, with a 5 bytes name in between
+        section.extend(&0x25u32.to_le_bytes());
+        section.extend(&0x20u32.to_le_bytes());
+        section.extend(b"bgm01");
+        // second_sect_addr and 4 bytes of padding
+        section.extend(&0x2Du32.to_le_bytes());
+        section.extend(&[0x11; 4]);
+        // Alignment bytes that aren't zeroes, to make sure they are kept
+        while !(start + section.len()).is_multiple_of(8) {
+            section.push(0xAA);
+        }
+        section.extend(&0x7D43D038u32.to_le_bytes());
+        section.extend((4..0x40u8).map(|i| i ^ 0x5A));
+        section.extend(&[0x22; 0x10]);
+
+        let size = section.len() as u32;
+        section[4..8].copy_from_slice(&size.to_le_bytes());
+        self.push(section)
+    }
+
+    /// A stereo DSP sound stored in the section itself, with two frames per channel
+    fn embedded(&mut self, link_id: u32) -> &mut Self {
+        let start = self.offset();
+        let mut section = vec![];
+        section.extend(&0x70CBCCC5u32.to_le_bytes());
+        section.extend(&0u32.to_le_bytes());
+        section.extend(&link_id.to_le_bytes());
+        section.extend(&[3, 0, 4, 0]);
+        section.extend(&1u32.to_le_bytes());
+        section.extend(&0x22u32.to_le_bytes());
+        section.extend(&0x20u32.to_le_bytes());
+        section.extend(b"se");
+        section.extend(&0x26u32.to_le_bytes());
+        while !(start + section.len()).is_multiple_of(8) {
+            section.push(0);
+        }
+
+        let mut sound = vec![];
+        for channel in 0..2u8 {
+            let mut header = vec![];
+            header.extend(&28u32.to_le_bytes());
+            header.extend(&32u32.to_le_bytes());
+            header.extend(&32000u32.to_le_bytes());
+            header.extend(&[1, 0, 0, 0]);
+            header.extend(&0x12u32.to_le_bytes());
+            header.extend(&0x1Fu32.to_le_bytes());
+            header.extend(&2u32.to_le_bytes());
+            header.extend((0..16i16).flat_map(|i| (i * 0x80 * (channel as i16 + 1)).to_le_bytes()));
+            header.extend(&[0, 0, 0x10 + channel, 0]);
+            header.resize(0x60, 0);
+            sound.extend(header);
+        }
+        for channel in 0..2u8 {
+            for frame in 0..2u8 {
+                sound.push(0x10 + channel);
+                sound.extend(&[0x10 * (channel + 1) + frame; DSP_FRAME_SIZE - 1]);
+            }
+        }
+
+        let data_offset = section.len() as u32 + 0x40;
+        section.extend(&0x2EB6CA6Fu32.to_le_bytes());
+        section.extend(&0x40u32.to_le_bytes());
+        section.extend(&0u32.to_le_bytes());
+        section.extend(&2u32.to_le_bytes());
+        section.extend(&2u32.to_le_bytes());
+        section.extend(&0u32.to_le_bytes());
+        section.extend(&32000u32.to_le_bytes());
+        section.extend(&28u32.to_le_bytes());
+        section.extend(&0u32.to_le_bytes());
+        section.extend(&14i32.to_le_bytes());
+        section.extend(&[0; 0xC]);
+        section.extend(&data_offset.to_le_bytes());
+        section.extend(&(sound.len() as u32).to_le_bytes());
+        section.extend(&0u32.to_le_bytes());
+        section.extend(sound);
+        // Trailing bytes that aren't part of the sound
+        section.extend(&[0x33; 0x10]);
+
+        let size = section.len() as u32;
+        section[4..8].copy_from_slice(&size.to_le_bytes());
+        self.push(section)
+    }
+
+    fn unknown(&mut self, size: u32) -> &mut Self {
+        let mut section = vec![];
+        section.extend(&0xF13BD2A9u32.to_le_bytes());
+        section.extend(&size.to_le_bytes());
+        section.extend(&0x5678u32.to_le_bytes());
+        section.extend((0..size - 0xC).map(|i| (i * 3) as u8));
+        self.push(section)
+    }
+
+    fn raw(&mut self, magic: u32, size: u32) -> &mut Self {
+        let mut section = vec![];
+        section.extend(&magic.to_le_bytes());
+        section.extend(&size.to_le_bytes());
+        section.extend((0..size - 0x8).map(|i| (i * 7) as u8));
+        self.push(section)
+    }
+
+    fn build(&mut self) -> Vec<u8> {
+        let size = (self.bytes.len() as u32).to_le_bytes();
+        self.bytes[0x18..0x1C].copy_from_slice(&size);
+        self.bytes[0x1C..0x20].copy_from_slice(&size);
+        self.bytes.clone()
+    }
+}
+
+fn asbin() -> Vec<u8> {
+    ArchiveBuilder::new(0x1A487B77)
+        .info(0xB7DB4B73, 0x40)
+        .info(0x241318, 0x30)
+        .companion(0x1000)
+        .padding(0x18)
+        .companion(0x1001)
+        .embedded(0x2000)
+        .unknown(0x24)
+        .raw(0xDEADBEEF, 0x1C)
+        .build()
+}
+
+fn stbin() -> Vec<u8> {
+    ArchiveBuilder::new(0xFCDD9402)
+        .music(0x1000)
+        .music(0x1001)
+        .build()
+}
+
+#[test]
+fn test_asbin_roundtrip() {
+    let bytes = asbin();
+    let ktsl = Ktsl::read(&mut Cursor::new(&bytes)).unwrap();
+
+    let kinds: Vec<&str> = ktsl.entries.iter().map(|section| match section {
+        Section::Info(_) => "info",
+        Section::Sound(_) => "sound",
+        Section::EmbeddedSound(_) => "embedded",
+        Section::Music(_) => "music",
+        Section::Padding(_) => "padding",
+        Section::Unknown(_) => "unknown",
+        Section::Raw(_) => "raw",
+    }).collect();
+    assert_eq!(kinds, ["info", "info", "sound", "padding", "sound", "embedded", "unknown", "raw"]);
+
+    Ktsl::verify_roundtrip(&bytes).unwrap();
+}
+
+#[test]
+fn test_stbin_roundtrip() {
+    let bytes = stbin();
+    let ktsl = Ktsl::read(&mut Cursor::new(&bytes)).unwrap();
+    assert_eq!(ktsl.get_music_sections().len(), 2);
+
+    Ktsl::verify_roundtrip(&bytes).unwrap();
+}
+
+#[test]
+fn test_kovs_entry() {
+    let bytes = ArchiveBuilder::new(0xFCDD9402).music(0x1000).kovs(0x1001).build();
+    let mut stbin = Ktsl::read(&mut Cursor::new(&bytes)).unwrap();
+    Ktsl::verify_roundtrip(&bytes).unwrap();
+
+    let music = stbin.get_music_section(0x1001).unwrap();
+    assert!(matches!(&music.payload, Payload::Kovs(kovs) if kovs.to_ogg().starts_with(b"OggS")));
+
+    let info = music.payload.info().unwrap();
+    assert_eq!((info.channel_count, info.sample_rate, info.sample_count), (1, 22050, 44100));
+    assert_eq!(info.loop_points, Some((100, 44000)));
+
+    let mut asbin = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+    stbin.set_loop(0x1001, Some((200, 1)), &mut asbin).unwrap();
+    assert_eq!(asbin.get_companion_sections()[1].loop_start, 200);
+    assert_eq!(asbin.get_companion_sections()[1].sample_rate, 22050);
+    assert_eq!(asbin.get_companion_sections()[1].channel_count, 1);
+}
+
+#[test]
+fn test_riff_entry() {
+    let bytes = ArchiveBuilder::new(0xFCDD9402).riff(0x1000).music(0x1001).build();
+    let mut stbin = Ktsl::read(&mut Cursor::new(&bytes)).unwrap();
+    Ktsl::verify_roundtrip(&bytes).unwrap();
+
+    let music = stbin.get_music_section(0x1000).unwrap();
+    assert_eq!(music.payload.codec_name(), "ATRAC9 (RIFF)");
+
+    let info = music.payload.info().unwrap();
+    assert_eq!((info.channel_count, info.sample_rate, info.sample_count, info.loop_points), (2, 48000, 9600, None));
+
+    // The smpl chunk makes the entry grow, so the following one has to move
+    let mut asbin = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+    stbin.set_loop(0x1000, Some((480, 4800)), &mut asbin).unwrap();
+
+    let offsets: Vec<u32> = stbin.section_offsets().map(|(offset, section)| offset + section.section_size()).collect();
+    assert_eq!(stbin.get_music_section(0x1000).unwrap().payload.info().unwrap().loop_points, Some((480, 4800)));
+    assert_eq!(asbin.get_companion_sections()[0].loop_start, 480);
+    assert_eq!(asbin.get_companion_sections()[1].ktss_offset, offsets[0] + 0x40);
+}
+
+#[test]
+fn test_validate() {
+    // The packets of the fixture are 10, 20 and 20ms long, short of the 60ms announced
+    let stbin = Ktsl::read(&mut Cursor::new(stbin())).unwrap();
+    let issue = KtssIssue::SampleCount { stored: 2880, actual: 2400 };
+    assert_eq!(stbin.validate().unwrap(), vec![(0x1000, issue), (0x1001, issue)]);
+
+    let mut music = stbin.get_music_section(0x1000).unwrap().clone();
+    assert_eq!(music.payload.recompute().unwrap(), vec![issue]);
+    assert!(music.payload.validate().unwrap().is_empty());
+    assert_eq!(music.payload.info().unwrap().sample_count, 2400);
+}
+
+#[test]
+fn test_summaries() {
+    let stbin = Ktsl::read(&mut Cursor::new(ArchiveBuilder::new(0xFCDD9402).music(0x1000).riff(0x1001).build())).unwrap();
+    let summaries = stbin.summaries();
+
+    assert_eq!(summaries.iter().map(|summary| (summary.kind, summary.offset, summary.link_id)).collect::<Vec<_>>(), vec![
+        ("entry", 0x40, Some(0x1000)),
+        ("entry", 0x40 + stbin.entries[0].section_size(), Some(0x1001)),
+    ]);
+    assert_eq!(summaries[1].codec.as_deref(), Some("ATRAC9 (RIFF)"));
+    assert_eq!(summaries[1].stream.unwrap().duration(), 0.2);
+
+    let asbin = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+    let companions: Vec<_> = asbin.summaries().into_iter().filter(|summary| summary.kind == "companion").collect();
+    assert_eq!(companions.len(), 2);
+    assert!(companions.iter().all(|summary| summary.stream.is_some() && summary.entry_location.is_some()));
+    assert!(asbin.summaries().iter().any(|summary| summary.kind == "raw" && summary.magic == 0xDEADBEEF && summary.link_id.is_none()));
+}
+
+#[test]
+fn test_unpack_falls_back_to_native_format() {
+    let stbin = Ktsl::read(&mut Cursor::new(ArchiveBuilder::new(0xFCDD9402).music(0x1000).riff(0x1001).dsp(0x1002).build())).unwrap();
+
+    let out_dir = std::env::temp_dir().join(format!("ktsl_tool_unpack_{}", std::process::id()));
+    let list = |format| {
+        let _ = std::fs::remove_dir_all(&out_dir);
+        std::fs::create_dir_all(&out_dir).unwrap();
+        stbin.unpack(&out_dir, format).unwrap();
+
+        let mut names: Vec<String> = std::fs::read_dir(&out_dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(list(ExportFormat::Ogg), vec!["00001000.opus", "00001001.at9", "00001002.ktss"]);
+    assert_eq!(list(ExportFormat::Wav), vec!["00001000.ktss", "00001001.at9", "00001002.wav"]);
+
+    std::fs::remove_dir_all(&out_dir).unwrap();
+}
+
+#[test]
+fn test_encoded_roundtrip() {
+    let mut ktsl = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+    ktsl.compressed = true;
+
+    let mut bytes = vec![];
+    ktsl.write(&mut bytes).unwrap();
+
+    Ktsl::verify_roundtrip(&bytes).unwrap();
+}
+
+#[test]
+fn test_roundtrip_after_edit() {
+    let mut ktsl = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+    ktsl.get_companion_sections()[0].ktss_offset = 0xFFFF;
+
+    let mut bytes = vec![];
+    ktsl.write(&mut bytes).unwrap();
+
+    assert!(bytes != asbin());
+    Ktsl::verify_roundtrip(&bytes).unwrap();
+}
+
+#[test]
+fn test_roundtrip_keeps_ktss_header() {
+    // The first KTSS starts at 0x80. Neither its codec start offset nor the unknown bytes before it are rewritten on an untouched file.
+    let mut bytes = stbin();
+    bytes[0xA4] ^= 0xFF;
+    bytes[0x88..0xA0].copy_from_slice(&[0x5A; 0x18]);
+    Ktsl::verify_roundtrip(&bytes).unwrap();
+
+    let mut written = vec![];
+    Ktsl::read(&mut Cursor::new(&bytes)).unwrap().write(&mut written).unwrap();
+    assert_eq!(written, bytes);
+}
+
+#[test]
+fn test_roundtrip_detects_changes() {
+    // The end of the header is compared as well on compressed archives
+    let mut ktsl = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+    ktsl.compressed = true;
+    let mut bytes = vec![];
+    ktsl.write(&mut bytes).unwrap();
+    bytes[0x30] = 1;
+    assert!(matches!(Ktsl::verify_roundtrip(&bytes), Err(Error::RoundTripMismatch { offset: 0x30 })));
+}
+
+#[test]
+fn test_set_loop() {
+    let mut stbin = Ktsl::read(&mut Cursor::new(stbin())).unwrap();
+    let mut asbin = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+
+    stbin.set_loop(0x1001, Some((960, 1920)), &mut asbin).unwrap();
+
+    let music = stbin.get_music_section(0x1001).unwrap();
+    assert_eq!(music.payload.info().unwrap().loop_points, Some((960, 1920)));
+    let companions = asbin.get_companion_sections();
+    assert_eq!(companions[1].loop_start, 960);
+
+    stbin.set_loop(0x1001, None, &mut asbin).unwrap();
+    assert_eq!(asbin.get_companion_sections()[1].loop_start, -1);
+
+    assert!(matches!(stbin.set_loop(0x1001, Some((960, 2880)), &mut asbin), Err(Error::InvalidLoop { .. })));
+    assert!(matches!(stbin.set_loop(0x2000, None, &mut asbin), Err(Error::MissingCompanion { link_id: 0x2000 })));
+}
+
+#[test]
+fn test_pack_points_companions_to_entries() {
+    let source = Ktsl::read(&mut Cursor::new(ArchiveBuilder::new(0xFCDD9402).dsp(0x1000).music(0x1001).build())).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("ktsl_tool_pack_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    source.unpack(&dir, ExportFormat::Ktss).unwrap();
+
+    let mut asbin = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+    let mut stbin = Ktsl::new_stbin();
+    stbin.pack(&dir, &mut asbin, false).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let mut bytes = vec![];
+    stbin.write(&mut bytes).unwrap();
+
+    for companion in asbin.get_companion_sections() {
+        let offset = companion.ktss_offset as usize;
+        assert_eq!(&bytes[offset..offset + 4], b"KTSS");
+        assert_eq!(companion.ktss_size, stbin.get_music_section(companion.header.link_id).unwrap().ktss_size);
+    }
+}
+
+#[test]
+fn test_save_pair() {
+    let stbin = Ktsl::read(&mut Cursor::new(stbin())).unwrap();
+    let asbin = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+
+    let dir = std::env::temp_dir().join(format!("ktsl_tool_save_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let stbin_path = dir.join("bgm.ktsl2stbin");
+    let asbin_path = dir.join("bgm.ktsl2asbin");
+    Ktsl::save_pair(&stbin, &stbin_path, &asbin, &asbin_path).unwrap();
+    Ktsl::verify_roundtrip(&std::fs::read(&stbin_path).unwrap()).unwrap();
+    Ktsl::verify_roundtrip(&std::fs::read(&asbin_path).unwrap()).unwrap();
+
+    // Neither archive is replaced when the second one can't be written
+    std::fs::remove_file(&stbin_path).unwrap();
+    assert!(Ktsl::save_pair(&stbin, &stbin_path, &asbin, dir.join("missing").join("bgm.ktsl2asbin")).is_err());
+    assert!(!stbin_path.exists());
+
+    let mut names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    names.sort();
+    assert_eq!(names, ["bgm.ktsl2asbin"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_embedded_sound() {
+    let mut asbin = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+
+    let ktss = asbin.get_ktss(0x2000).unwrap();
+    assert_eq!(ktss.codec, KtssCodec::Dsp);
+    assert_eq!((ktss.channel_count, ktss.sample_rate, ktss.sample_count), (2, 32000, 28));
+    assert_eq!((ktss.loop_start, ktss.loop_length), (14, 14));
+
+    let dsp = match &ktss.body {
+        KtssBody::Dsp(dsp) => dsp,
+        body => panic!("Expected a DSP body, got {:?}", body),
+    };
+    assert_eq!(dsp.channels[1].coefficients[1], 0x100);
+    assert_eq!(dsp.channels[1].predictor_scale, 0x11);
+
+    // The channels are stored one after the other, but KTSS interleave their frames
+    let frames: Vec<Vec<u8>> = (0..2).map(|channel| dsp.channel_frames(channel).map(|frame| frame[1]).collect()).collect();
+    assert_eq!(frames, [[0x10, 0x11], [0x20, 0x21]]);
+    assert_eq!(ktsl_tool::dsp::to_wav(&ktss, 0).unwrap().frame_count(), 28);
+
+    let summary = asbin.summaries().into_iter().find(|summary| summary.link_id == Some(0x2000)).unwrap();
+    assert_eq!(summary.codec.as_deref(), Some("Dsp (embedded)"));
+
+    // Not a companion, so nothing points it to the Ktsl2stbin
+    assert!(asbin.get_companion_sections().iter().all(|companion| companion.header.link_id != 0x2000));
+}
+
+#[test]
+fn test_add_embedded_sound() {
+    let frames = 14 * 40;
+    let samples: Vec<i16> = (0..frames).flat_map(|i| {
+        let tone = ((i as f64 / 32000.0) * 440.0 * std::f64::consts::TAU).sin() * 12000.0;
+        vec![tone as i16, (tone * -0.5) as i16]
+    }).collect();
+    let wav = Wav { channel_count: 2, sample_rate: 32000, samples, loop_points: Some((14 * 10, frames as u32 - 14 * 10)) };
+    let ktss = ktsl_tool::dsp::encode_ktss(&wav);
+
+    let mut asbin = Ktsl::read(&mut Cursor::new(asbin())).unwrap();
+    asbin.add_sound(0x3000, "se_new", &ktss).unwrap();
+    assert!(matches!(asbin.add_sound(0x2000, "se_dup", &ktss), Err(Error::DuplicateLinkId { link_id: 0x2000 })));
+
+    let mut bytes = vec![];
+    asbin.write(&mut bytes).unwrap();
+    Ktsl::verify_roundtrip(&bytes).unwrap();
+
+    let read = Ktsl::read(&mut Cursor::new(&bytes)).unwrap();
+    assert!(matches!(read.entries.last(), Some(Section::EmbeddedSound(sound)) if sound.header.link_id == 0x3000));
+
+    let embedded = read.get_ktss(0x3000).unwrap();
+    assert_eq!((embedded.loop_start, embedded.loop_length), (ktss.loop_start, ktss.loop_length));
+
+    // The loop context captured by the encoder makes it through the DSP headers
+    match (&embedded.body, &ktss.body) {
+        (KtssBody::Dsp(embedded), KtssBody::Dsp(encoded)) => assert_eq!(embedded.channels, encoded.channels),
+        bodies => panic!("Expected DSP bodies, got {:?}", bodies),
+    }
+
+    let expected = ktsl_tool::dsp::to_wav(&ktss, 1).unwrap();
+    let decoded = ktsl_tool::dsp::to_wav(&embedded, 1).unwrap();
+    assert_eq!(decoded.samples, expected.samples);
+    assert_eq!(decoded.loop_points, expected.loop_points);
+}